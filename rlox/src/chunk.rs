@@ -0,0 +1,874 @@
+use std::fmt::{self, Write};
+use std::rc::Rc;
+
+use crate::value::{Function, Value, ValueArray};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    Equal,
+    Greater,
+    Less,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    Return,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    GetLocal,
+    SetLocal,
+    Modulo,
+    PopN,
+    Call,
+    Dup,
+}
+
+/// InvalidOpCode is the error for a byte that doesn't decode to any opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidOpCode(pub u8);
+
+impl fmt::Display for InvalidOpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown opcode {}", self.0)
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = InvalidOpCode;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        let code = match byte {
+            0 => OpCode::Constant,
+            1 => OpCode::Nil,
+            2 => OpCode::True,
+            3 => OpCode::False,
+            4 => OpCode::Pop,
+            5 => OpCode::GetGlobal,
+            6 => OpCode::DefineGlobal,
+            7 => OpCode::SetGlobal,
+            8 => OpCode::Equal,
+            9 => OpCode::Greater,
+            10 => OpCode::Less,
+            11 => OpCode::Add,
+            12 => OpCode::Subtract,
+            13 => OpCode::Multiply,
+            14 => OpCode::Divide,
+            15 => OpCode::Not,
+            16 => OpCode::Negate,
+            17 => OpCode::Print,
+            18 => OpCode::Return,
+            19 => OpCode::Jump,
+            20 => OpCode::JumpIfFalse,
+            21 => OpCode::Loop,
+            22 => OpCode::GetLocal,
+            23 => OpCode::SetLocal,
+            24 => OpCode::Modulo,
+            25 => OpCode::PopN,
+            26 => OpCode::Call,
+            27 => OpCode::Dup,
+            _ => return Err(InvalidOpCode(byte)),
+        };
+        Ok(code)
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(code: OpCode) -> Self {
+        code as u8
+    }
+}
+
+/// ChunkDecodeError is why Chunk::deserialize rejected its input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkDecodeError {
+    /// The input doesn't start with MAGIC, so it isn't a serialized chunk.
+    BadMagic,
+    /// The chunk was written by a different version of the format.
+    UnsupportedVersion(u8),
+    /// The input ends partway through the chunk.
+    UnexpectedEnd,
+    /// There are bytes left over after the chunk.
+    TrailingBytes,
+    InvalidConstantTag(u8),
+    InvalidString,
+    /// The line runs don't start at offset 0, go backwards, or run past the code.
+    InvalidLineTable,
+    /// The instruction at the offset loads a constant that isn't in the pool.
+    ConstantOutOfRange(usize),
+}
+
+impl fmt::Display for ChunkDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkDecodeError::BadMagic => write!(f, "Not a compiled chunk"),
+            ChunkDecodeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported chunk format version {}", version)
+            }
+            ChunkDecodeError::UnexpectedEnd => write!(f, "Chunk ends unexpectedly"),
+            ChunkDecodeError::TrailingBytes => write!(f, "Unexpected bytes after chunk"),
+            ChunkDecodeError::InvalidConstantTag(tag) => write!(f, "Unknown constant tag {}", tag),
+            ChunkDecodeError::InvalidString => write!(f, "String constant is not valid UTF-8"),
+            ChunkDecodeError::InvalidLineTable => write!(f, "Malformed line table"),
+            ChunkDecodeError::ConstantOutOfRange(offset) => {
+                write!(f, "Instruction at {} loads a missing constant", offset)
+            }
+        }
+    }
+}
+
+/// Identifies a serialized chunk.
+const MAGIC: &[u8; 4] = b"RLOX";
+/// Bump whenever the serialized layout or the opcode numbering changes.
+const FORMAT_VERSION: u8 = 1;
+
+// Tags for each kind of constant in a serialized pool.
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+/// LineStart marks the offset where a run of instructions from the same line begins.
+#[derive(Debug, Clone, Copy)]
+struct LineStart {
+    offset: usize,
+    line: usize,
+}
+
+/// Chunk is a sequence of bytecode along with its constants and line information.
+#[derive(Debug, Default, Clone)]
+pub struct Chunk {
+    code: Vec<u8>,
+    lines: Vec<LineStart>,
+    constants: ValueArray,
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            lines: Vec::new(),
+            constants: ValueArray::new(),
+        }
+    }
+
+    /// Appends a byte, run-length encoding its line.
+    pub fn write(&mut self, byte: u8, line: usize) {
+        self.code.push(byte);
+
+        if let Some(last) = self.lines.last() {
+            if last.line == line {
+                return;
+            }
+        }
+        self.lines.push(LineStart {
+            offset: self.code.len() - 1,
+            line,
+        });
+    }
+
+    pub fn write_opcode(&mut self, op: OpCode, line: usize) {
+        self.write(op.into(), line);
+    }
+
+    /// Adds value to the constant pool and writes an OP_CONSTANT that loads it.
+    /// Returns the constant's index, or None without writing anything if the index
+    /// doesn't fit in the one-byte operand.
+    pub fn write_constant(&mut self, value: Value, line: usize) -> Option<u8> {
+        let constant = u8::try_from(self.add_constants(value)).ok()?;
+        self.write_opcode(OpCode::Constant, line);
+        self.write(constant, line);
+        Some(constant)
+    }
+
+    /// Overwrites the byte at offset, for filling in operands that weren't known when emitted.
+    pub fn patch(&mut self, offset: usize, byte: u8) {
+        self.code[offset] = byte;
+    }
+
+    /// Drops the code from offset on and the constants from index constant on, so the
+    /// compiler can replace instructions it has just written.
+    pub fn truncate(&mut self, offset: usize, constant: usize) {
+        self.code.truncate(offset);
+        self.lines.retain(|start| start.offset < offset);
+        self.constants.truncate(constant);
+    }
+
+    /// Returns the number of bytes of code written so far.
+    pub fn count(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// Adds a value to the constant pool and returns its index, reusing the index of an
+    /// identical value already in the pool.
+    pub fn add_constants(&mut self, value: Value) -> usize {
+        match self.constants.find(&value) {
+            Some(index) => index,
+            None => self.constants.write(value),
+        }
+    }
+
+    /// Returns the byte at offset, or None past the end of the code.
+    pub fn read(&self, offset: usize) -> Option<u8> {
+        self.code.get(offset).copied()
+    }
+
+    pub fn get_constant(&self, index: usize) -> Value {
+        self.constants.read(index)
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        self.constants.as_slice()
+    }
+
+    /// Returns the source line of the instruction at offset.
+    /// An empty chunk has no lines, so it reports line 0.
+    pub fn get_line(&self, offset: usize) -> usize {
+        let index = self.lines.partition_point(|start| start.offset <= offset);
+        index
+            .checked_sub(1)
+            .map_or(0, |index| self.lines[index].line)
+    }
+
+    /// Disassembles the whole chunk under a `== name ==` header.
+    pub fn disassemble(&self, name: &str) -> String {
+        let mut out = String::new();
+        self.disassemble_to(name, &mut out).unwrap();
+        out
+    }
+
+    /// Like disassemble, but writes the text to out as it goes instead of building a String.
+    pub fn disassemble_to<W: Write>(&self, name: &str, out: &mut W) -> fmt::Result {
+        writeln!(out, "== {} ==", name)?;
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            offset = self.disassemble_instruction_to(offset, out)?;
+        }
+        Ok(())
+    }
+
+    /// Disassembles the instruction at offset. Returns the offset of the next instruction
+    /// along with the text, which ends in a newline.
+    pub fn disassemble_instruction(&self, offset: usize) -> (usize, String) {
+        let mut out = String::new();
+        let next = self.disassemble_instruction_to(offset, &mut out).unwrap();
+        (next, out)
+    }
+
+    /// Writes the disassembly of the instruction at offset to out and returns the offset
+    /// of the next instruction.
+    pub fn disassemble_instruction_to<W: Write>(
+        &self,
+        offset: usize,
+        out: &mut W,
+    ) -> Result<usize, fmt::Error> {
+        write!(out, "{:04} ", offset)?;
+        let line = self.get_line(offset);
+        if offset > 0 && line == self.get_line(offset - 1) {
+            write!(out, "   | ")?;
+        } else {
+            write!(out, "{:4} ", line)?;
+        }
+
+        let Some(byte) = self.read(offset) else {
+            writeln!(out, "End of code")?;
+            return Ok(offset + 1);
+        };
+        let instruction = match OpCode::try_from(byte) {
+            Ok(instruction) => instruction,
+            Err(err) => {
+                writeln!(out, "{}", err)?;
+                return Ok(offset + 1);
+            }
+        };
+        match instruction {
+            OpCode::Constant => self.constant_instruction(out, "OP_CONSTANT", offset),
+            OpCode::Nil => self.simple_instruction(out, "OP_NIL", offset),
+            OpCode::True => self.simple_instruction(out, "OP_TRUE", offset),
+            OpCode::False => self.simple_instruction(out, "OP_FALSE", offset),
+            OpCode::Pop => self.simple_instruction(out, "OP_POP", offset),
+            OpCode::Dup => self.simple_instruction(out, "OP_DUP", offset),
+            OpCode::PopN => self.byte_instruction(out, "OP_POPN", offset),
+            OpCode::GetGlobal => self.constant_instruction(out, "OP_GET_GLOBAL", offset),
+            OpCode::DefineGlobal => self.constant_instruction(out, "OP_DEFINE_GLOBAL", offset),
+            OpCode::SetGlobal => self.constant_instruction(out, "OP_SET_GLOBAL", offset),
+            OpCode::Equal => self.simple_instruction(out, "OP_EQUAL", offset),
+            OpCode::Greater => self.simple_instruction(out, "OP_GREATER", offset),
+            OpCode::Less => self.simple_instruction(out, "OP_LESS", offset),
+            OpCode::Add => self.simple_instruction(out, "OP_ADD", offset),
+            OpCode::Subtract => self.simple_instruction(out, "OP_SUBTRACT", offset),
+            OpCode::Multiply => self.simple_instruction(out, "OP_MULTIPLY", offset),
+            OpCode::Divide => self.simple_instruction(out, "OP_DIVIDE", offset),
+            OpCode::Modulo => self.simple_instruction(out, "OP_MODULO", offset),
+            OpCode::Not => self.simple_instruction(out, "OP_NOT", offset),
+            OpCode::Negate => self.simple_instruction(out, "OP_NEGATE", offset),
+            OpCode::Print => self.simple_instruction(out, "OP_PRINT", offset),
+            OpCode::Call => self.byte_instruction(out, "OP_CALL", offset),
+            OpCode::Return => self.simple_instruction(out, "OP_RETURN", offset),
+            OpCode::Jump => self.jump_instruction(out, "OP_JUMP", 1, offset),
+            OpCode::JumpIfFalse => self.jump_instruction(out, "OP_JUMP_IF_FALSE", 1, offset),
+            OpCode::Loop => self.jump_instruction(out, "OP_LOOP", -1, offset),
+            OpCode::GetLocal => self.byte_instruction(out, "OP_GET_LOCAL", offset),
+            OpCode::SetLocal => self.byte_instruction(out, "OP_SET_LOCAL", offset),
+        }
+    }
+
+    /// Encodes the chunk as bytes that deserialize can turn back into the same chunk.
+    ///
+    /// The layout is MAGIC and FORMAT_VERSION, then the code, the line table and the
+    /// constant pool, each prefixed by its length. Integers are little-endian u32s.
+    /// A function constant is its arity and name followed by its own chunk, minus the header.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(FORMAT_VERSION);
+        self.write_body(&mut out);
+        out
+    }
+
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_u32(out, self.code.len());
+        out.extend_from_slice(&self.code);
+
+        write_u32(out, self.lines.len());
+        for start in &self.lines {
+            write_u32(out, start.offset);
+            write_u32(out, start.line);
+        }
+
+        let constants = self.constants.as_slice();
+        write_u32(out, constants.len());
+        for constant in constants {
+            match constant {
+                Value::Nil => out.push(TAG_NIL),
+                Value::Bool(false) => out.push(TAG_FALSE),
+                Value::Bool(true) => out.push(TAG_TRUE),
+                Value::Number(number) => {
+                    out.push(TAG_NUMBER);
+                    out.extend_from_slice(&number.to_le_bytes());
+                }
+                Value::Str(string) => {
+                    out.push(TAG_STRING);
+                    write_u32(out, string.len());
+                    out.extend_from_slice(string.as_bytes());
+                }
+                Value::Function(function) => {
+                    out.push(TAG_FUNCTION);
+                    write_u32(out, function.arity);
+                    // Only the script has no name, and it is never a constant.
+                    let name = function.name.as_deref().unwrap_or_default();
+                    write_u32(out, name.len());
+                    out.extend_from_slice(name.as_bytes());
+                    function.chunk.write_body(out);
+                }
+                Value::NativeFn(_) => unreachable!("natives are never compiled into constants"),
+            }
+        }
+    }
+
+    /// Decodes a chunk written by serialize.
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, ChunkDecodeError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(ChunkDecodeError::BadMagic);
+        }
+        let version = reader.u8()?;
+        if version != FORMAT_VERSION {
+            return Err(ChunkDecodeError::UnsupportedVersion(version));
+        }
+
+        let chunk = Chunk::read_body(&mut reader)?;
+        if !reader.bytes.is_empty() {
+            return Err(ChunkDecodeError::TrailingBytes);
+        }
+        Ok(chunk)
+    }
+
+    fn read_body(reader: &mut Reader) -> Result<Chunk, ChunkDecodeError> {
+        let mut chunk = Chunk::new();
+        let code_len = reader.u32()?;
+        chunk.code = reader.take(code_len)?.to_vec();
+
+        for _ in 0..reader.u32()? {
+            let offset = reader.u32()?;
+            let line = reader.u32()?;
+            chunk.lines.push(LineStart { offset, line });
+        }
+
+        for _ in 0..reader.u32()? {
+            let constant = match reader.u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Bool(false),
+                TAG_TRUE => Value::Bool(true),
+                TAG_NUMBER => {
+                    let bytes = reader.take(8)?.try_into().unwrap();
+                    Value::Number(f64::from_le_bytes(bytes))
+                }
+                TAG_STRING => Value::Str(reader.string()?.into()),
+                TAG_FUNCTION => {
+                    let arity = reader.u32()?;
+                    let name = reader.string()?;
+                    let function = Function {
+                        arity,
+                        name: Some(name.into()),
+                        chunk: Chunk::read_body(reader)?,
+                    };
+                    Value::Function(Rc::new(function))
+                }
+                tag => return Err(ChunkDecodeError::InvalidConstantTag(tag)),
+            };
+            // Not add_constants: the pool must keep its indexes even if it has duplicates.
+            chunk.constants.write(constant);
+        }
+        chunk.validate()?;
+        Ok(chunk)
+    }
+
+    /// Checks what get_line and the constant instructions rely on, so a decoded chunk
+    /// can't make them index out of bounds.
+    fn validate(&self) -> Result<(), ChunkDecodeError> {
+        let runs_start_at_zero = match self.lines.first() {
+            Some(first) => first.offset == 0,
+            None => self.code.is_empty(),
+        };
+        let runs_increase = self
+            .lines
+            .windows(2)
+            .all(|pair| pair[0].offset < pair[1].offset);
+        let runs_fit = self
+            .lines
+            .last()
+            .is_none_or(|last| last.offset < self.code.len());
+        if !(runs_start_at_zero && runs_increase && runs_fit) {
+            return Err(ChunkDecodeError::InvalidLineTable);
+        }
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let Ok(instruction) = OpCode::try_from(self.code[offset]) else {
+                offset += 1;
+                continue;
+            };
+            let operands = match instruction {
+                OpCode::Constant | OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal => {
+                    let constant = self.code.get(offset + 1).copied();
+                    if constant.is_some_and(|index| usize::from(index) >= self.constants().len()) {
+                        return Err(ChunkDecodeError::ConstantOutOfRange(offset));
+                    }
+                    1
+                }
+                OpCode::PopN | OpCode::Call | OpCode::GetLocal | OpCode::SetLocal => 1,
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => 2,
+                _ => 0,
+            };
+            offset += 1 + operands;
+        }
+        Ok(())
+    }
+
+    fn simple_instruction<W: Write>(
+        &self,
+        out: &mut W,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        writeln!(out, "{}", name)?;
+        Ok(offset + 1)
+    }
+
+    fn constant_instruction<W: Write>(
+        &self,
+        out: &mut W,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        let Some(constant) = self.read(offset + 1) else {
+            return self.truncated_instruction(out, name);
+        };
+        writeln!(
+            out,
+            "{:<16} {:4} '{}'",
+            name,
+            constant,
+            self.get_constant(constant as usize)
+        )?;
+        Ok(offset + 2)
+    }
+
+    /// Prints an instruction whose operand is a stack slot or count.
+    fn byte_instruction<W: Write>(
+        &self,
+        out: &mut W,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        let Some(slot) = self.read(offset + 1) else {
+            return self.truncated_instruction(out, name);
+        };
+        writeln!(out, "{:<16} {:4}", name, slot)?;
+        Ok(offset + 2)
+    }
+
+    /// Prints a jump with its 16-bit operand resolved to the target offset.
+    fn jump_instruction<W: Write>(
+        &self,
+        out: &mut W,
+        name: &str,
+        sign: i64,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        let (Some(high), Some(low)) = (self.read(offset + 1), self.read(offset + 2)) else {
+            return self.truncated_instruction(out, name);
+        };
+        let jump = u16::from_be_bytes([high, low]);
+        let target = offset as i64 + 3 + sign * i64::from(jump);
+        writeln!(out, "{:<16} {:4} -> {}", name, offset, target)?;
+        Ok(offset + 3)
+    }
+
+    /// Prints an instruction whose operands run past the end of the code.
+    fn truncated_instruction<W: Write>(
+        &self,
+        out: &mut W,
+        name: &str,
+    ) -> Result<usize, fmt::Error> {
+        writeln!(out, "{:<16} <truncated>", name)?;
+        Ok(self.code.len())
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("chunk too large to serialize");
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Reader walks through the input to Chunk::deserialize.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ChunkDecodeError> {
+        if self.bytes.len() < len {
+            return Err(ChunkDecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ChunkDecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, ChunkDecodeError> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+
+    /// Reads a length-prefixed UTF-8 string.
+    fn string(&mut self) -> Result<&'a str, ChunkDecodeError> {
+        let len = self.u32()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| ChunkDecodeError::InvalidString)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constants_round_trip() {
+        let mut chunk = Chunk::new();
+        let values = [
+            Value::Number(1.5),
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Nil,
+            Value::Str("lox".into()),
+        ];
+        for value in values {
+            let index = chunk.add_constants(value.clone());
+            assert_eq!(chunk.get_constant(index), value);
+        }
+    }
+
+    #[test]
+    fn count_tracks_bytes_written() {
+        let mut chunk = Chunk::new();
+        assert!(chunk.is_empty());
+
+        chunk.write_opcode(OpCode::Nil, 1);
+        assert_eq!(chunk.count(), 1);
+        assert_eq!(chunk.write_constant(Value::Number(2.0), 1), Some(0));
+        assert_eq!(chunk.count(), 3);
+        assert!(!chunk.is_empty());
+        assert_eq!(chunk.read(1), Some(u8::from(OpCode::Constant)));
+    }
+
+    #[test]
+    fn write_constant_needs_a_one_byte_index() {
+        let mut chunk = Chunk::new();
+        for n in 0..256 {
+            chunk.add_constants(Value::Number(n as f64));
+        }
+        assert_eq!(chunk.write_constant(Value::Nil, 1), None);
+        assert!(chunk.is_empty());
+    }
+
+    #[test]
+    fn reuses_identical_constants() {
+        let mut chunk = Chunk::new();
+        assert_eq!(chunk.add_constants(Value::Number(1.0)), 0);
+        assert_eq!(chunk.add_constants(Value::Number(1.0)), 0);
+        assert_eq!(chunk.add_constants(Value::Number(-0.0)), 1);
+        assert_eq!(chunk.add_constants(Value::Number(0.0)), 2);
+        assert_eq!(chunk.add_constants(Value::Str("1".into())), 3);
+        assert_eq!(chunk.add_constants(Value::Str("1".into())), 3);
+    }
+
+    #[test]
+    fn truncate_drops_code_lines_and_constants() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(1.0), 1);
+        chunk.write_constant(Value::Number(2.0), 2);
+        chunk.write_opcode(OpCode::Add, 3);
+
+        chunk.truncate(2, 1);
+        assert_eq!(chunk.count(), 2);
+        assert_eq!(chunk.constants(), [Value::Number(1.0)]);
+
+        chunk.write_opcode(OpCode::Return, 4);
+        assert_eq!((chunk.get_line(1), chunk.get_line(2)), (1, 4));
+    }
+
+    #[test]
+    fn decodes_opcodes() {
+        assert_eq!(
+            OpCode::try_from(u8::from(OpCode::Negate)),
+            Ok(OpCode::Negate)
+        );
+        assert_eq!(OpCode::try_from(27), Ok(OpCode::Dup));
+        assert_eq!(OpCode::try_from(99), Err(InvalidOpCode(99)));
+    }
+
+    #[test]
+    fn disassembles_to_a_string() {
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constants(Value::Number(1.2)) as u8;
+        chunk.write(OpCode::Constant.into(), 123);
+        chunk.write(constant, 123);
+        chunk.write(OpCode::Return.into(), 123);
+
+        assert_eq!(
+            chunk.disassemble("test chunk"),
+            "== test chunk ==\n\
+             0000  123 OP_CONSTANT         0 '1.2'\n\
+             0002    | OP_RETURN\n"
+        );
+        assert_eq!(
+            chunk.disassemble_instruction(2),
+            (3, "0002    | OP_RETURN\n".to_string())
+        );
+    }
+
+    /// Counts the lines written to it without keeping the text.
+    #[derive(Default)]
+    struct LineCounter(usize);
+
+    impl Write for LineCounter {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 += s.matches('\n').count();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn disassembles_to_any_writer() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Nil, 1);
+        chunk.write_opcode(OpCode::Print, 1);
+        chunk.write_opcode(OpCode::Return, 2);
+
+        let mut text = String::from("before\n");
+        chunk.disassemble_to("test chunk", &mut text).unwrap();
+        assert_eq!(text, format!("before\n{}", chunk.disassemble("test chunk")));
+
+        let mut counter = LineCounter::default();
+        chunk.disassemble_to("test chunk", &mut counter).unwrap();
+        assert_eq!(counter.0, 4);
+        assert_eq!(chunk.disassemble_instruction_to(2, &mut counter), Ok(3));
+        assert_eq!(counter.0, 5);
+    }
+
+    #[test]
+    fn disassembles_jumps_to_their_target() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::JumpIfFalse.into(), 1);
+        chunk.write(0, 1);
+        chunk.write(2, 1);
+        chunk.write(OpCode::Pop.into(), 1);
+        chunk.write(OpCode::Nil.into(), 1);
+        chunk.write(OpCode::Return.into(), 1);
+
+        assert_eq!(chunk.count(), 6);
+        assert_eq!(
+            chunk.disassemble_instruction(0),
+            (3, "0000    1 OP_JUMP_IF_FALSE    0 -> 5\n".to_string())
+        );
+
+        chunk.write(OpCode::Loop.into(), 1);
+        chunk.write(0, 1);
+        chunk.write(9, 1);
+        assert_eq!(
+            chunk.disassemble_instruction(6),
+            (9, "0006    | OP_LOOP             6 -> 0\n".to_string())
+        );
+    }
+
+    #[test]
+    fn disassembles_truncated_instructions() {
+        let mut chunk = Chunk::new();
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.write_opcode(OpCode::Jump, 1);
+        chunk.write(0, 1);
+
+        assert_eq!(
+            chunk.disassemble_instruction(1),
+            (3, "0001    | OP_JUMP          <truncated>\n".to_string())
+        );
+        assert_eq!(
+            chunk.disassemble_instruction(3),
+            (4, "0003    | End of code\n".to_string())
+        );
+    }
+
+    #[test]
+    fn serialized_chunks_round_trip() {
+        use crate::compiler::Compiler;
+
+        let source = "var greeting = \"héllo\";\n\
+                      {\n  var n = -0.5;\n  if (n < 0 and true) print greeting + \"!\";\n}\n\
+                      print nil == false;\n\
+                      fun add(a, b) { print a + b; }";
+        let chunk = Compiler::new(source).compile().unwrap();
+        let bytes = chunk.serialize();
+        assert!(bytes.starts_with(b"RLOX"));
+
+        let decoded = Chunk::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.disassemble("script"), chunk.disassemble("script"));
+        assert_eq!(decoded.serialize(), bytes);
+
+        let Some(Value::Function(add)) = decoded.constants().last() else {
+            panic!("expected add to be the last constant");
+        };
+        assert_eq!((add.arity, add.name.as_deref()), (2, Some("add")));
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Str("lox".into()), 1);
+        chunk.write_opcode(OpCode::Return, 2);
+        let bytes = chunk.serialize();
+
+        assert_eq!(
+            Chunk::deserialize(b"LOX!\x01").unwrap_err(),
+            ChunkDecodeError::BadMagic
+        );
+        let mut newer = bytes.clone();
+        newer[4] = FORMAT_VERSION + 1;
+        assert_eq!(
+            Chunk::deserialize(&newer).unwrap_err(),
+            ChunkDecodeError::UnsupportedVersion(FORMAT_VERSION + 1)
+        );
+        assert_eq!(
+            Chunk::deserialize(&bytes[..bytes.len() - 1]).unwrap_err(),
+            ChunkDecodeError::UnexpectedEnd
+        );
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            Chunk::deserialize(&longer).unwrap_err(),
+            ChunkDecodeError::TrailingBytes
+        );
+        let mut bad_utf8 = bytes.clone();
+        let last = bad_utf8.len() - 1;
+        bad_utf8[last] = 0xff;
+        assert_eq!(
+            Chunk::deserialize(&bad_utf8).unwrap_err(),
+            ChunkDecodeError::InvalidString
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_line_tables() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(1.0), 1);
+        chunk.write_opcode(OpCode::Return, 2);
+        let bytes = chunk.serialize();
+        // After the header and the three bytes of code come the run count and then
+        // each run's offset and line.
+        let first_offset = 5 + 4 + 3 + 4;
+        let second_offset = first_offset + 8;
+
+        for (at, offset) in [(first_offset, 1), (second_offset, 0), (second_offset, 3)] {
+            let mut bad = bytes.clone();
+            bad[at..at + 4].copy_from_slice(&u32::to_le_bytes(offset));
+            assert_eq!(
+                Chunk::deserialize(&bad).unwrap_err(),
+                ChunkDecodeError::InvalidLineTable,
+                "run offset {} at byte {}",
+                offset,
+                at
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_constant_operands_outside_the_pool() {
+        let mut chunk = Chunk::new();
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.write_constant(Value::Number(1.0), 1);
+        chunk.write_opcode(OpCode::Return, 1);
+        let mut bytes = chunk.serialize();
+        // The OP_CONSTANT's operand is the third byte of code.
+        bytes[5 + 4 + 2] = 1;
+        assert_eq!(
+            Chunk::deserialize(&bytes).unwrap_err(),
+            ChunkDecodeError::ConstantOutOfRange(1)
+        );
+    }
+
+    #[test]
+    fn lines_are_run_length_encoded() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Constant.into(), 1);
+        chunk.write(0, 1);
+        chunk.write(OpCode::Negate.into(), 2);
+        chunk.write(OpCode::Return.into(), 4);
+
+        assert_eq!(chunk.lines.len(), 3);
+        assert_eq!(chunk.get_line(1), 1);
+        assert_eq!(chunk.get_line(2), 2);
+        assert_eq!(chunk.get_line(3), 4);
+    }
+}
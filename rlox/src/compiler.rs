@@ -0,0 +1,1894 @@
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::scanner::{unescape, Scanner};
+use crate::token::{Token, TokenType};
+use crate::value::{Function, Value};
+
+/// Compiler is a single-pass Pratt parser that emits bytecode straight into a chunk.
+///
+/// The chunk, locals and scope depth belong to the function being compiled. A nested
+/// function declaration sets them aside in a FunctionState and restores them at its end.
+pub struct Compiler {
+    parser: Parser,
+    scanner: Scanner,
+    chunk: Chunk,
+    kind: FunctionKind,
+    /// Locals in scope, in the order of their stack slots.
+    locals: Vec<Local>,
+    /// How many blocks deep the code being compiled is. Zero is global scope.
+    scope_depth: usize,
+    /// The loops enclosing the code being compiled, innermost last.
+    loops: Vec<Loop>,
+    /// The number literal most recently emitted, for constant folding.
+    last_number: Option<NumberLiteral>,
+    /// Prints the disassembly of each chunk finished without errors to stderr. On by
+    /// default with the debug_print_code feature.
+    print_code: bool,
+}
+
+/// NumberLiteral is an OP_CONSTANT that loads a number known at compile time.
+#[derive(Debug, Clone, Copy)]
+struct NumberLiteral {
+    value: f64,
+    /// The offset of the instruction.
+    start: usize,
+    /// The offset just past the instruction.
+    end: usize,
+    /// The size of the constant pool before the instruction was emitted.
+    constants: usize,
+}
+
+/// FunctionKind says whether the compiler is in a function body or top-level code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Function,
+    Script,
+}
+
+/// The state of an enclosing function while a nested one is compiled.
+struct FunctionState {
+    chunk: Chunk,
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    loops: Vec<Loop>,
+}
+
+/// Loop is a while or for loop being compiled, which break and continue refer to.
+struct Loop {
+    /// Where continue jumps to: the increment, or the condition if there is none.
+    start: usize,
+    /// The scope depth around the body. Leaving the loop pops the locals deeper than this.
+    scope_depth: usize,
+    /// The operands of the jumps emitted for break, patched once the loop's end is known.
+    breaks: Vec<usize>,
+}
+
+/// The most locals in scope at once, since a local's slot is a one-byte operand.
+const LOCALS_MAX: usize = 256;
+
+/// Local is a variable declared in a block, living in a stack slot.
+struct Local {
+    name: Token,
+    /// The scope depth it was declared at, or None until its initializer has run.
+    depth: Option<usize>,
+}
+
+#[derive(Default)]
+struct Parser {
+    current: Token,
+    previous: Token,
+    had_error: RefCell<bool>,
+    /// Set after an error until the parser resynchronizes, to suppress cascading errors.
+    panic_mode: RefCell<bool>,
+    /// Every error reported so far, formatted as printed.
+    errors: RefCell<Vec<String>>,
+    /// Set if the first error was at the end of the source, meaning more input might fix it.
+    incomplete: RefCell<bool>,
+    /// Collects errors without printing them.
+    quiet: bool,
+}
+
+/// Precedence levels, from lowest to highest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Assignment,  // =
+    Conditional, // ?:
+    Or,          // or
+    And,         // and
+    Equality,    // == !=
+    Comparison,  // < > <= >=
+    Term,        // + -
+    Factor,      // * / %
+    Unary,       // ! -
+    Call,        // . ()
+    Primary,
+}
+
+impl Precedence {
+    /// Returns the next higher precedence level.
+    fn next(self) -> Self {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Conditional,
+            Precedence::Conditional => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+/// A parse function. The flag says whether the expression may be an assignment target.
+type ParseFn = fn(&mut Compiler, bool);
+
+#[derive(Clone, Copy)]
+struct ParseRule {
+    prefix: Option<ParseFn>,
+    infix: Option<ParseFn>,
+    precedence: Precedence,
+}
+
+impl ParseRule {
+    fn new(prefix: Option<ParseFn>, infix: Option<ParseFn>, precedence: Precedence) -> Self {
+        Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+/// The parse rule table, keyed by the token that starts or continues an expression.
+fn get_rule(ttype: TokenType) -> ParseRule {
+    match ttype {
+        TokenType::LeftParen => ParseRule::new(
+            Some(Compiler::grouping),
+            Some(Compiler::call),
+            Precedence::Call,
+        ),
+        TokenType::Minus => ParseRule::new(
+            Some(Compiler::unary),
+            Some(Compiler::binary),
+            Precedence::Term,
+        ),
+        TokenType::Plus => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
+        TokenType::Slash => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        TokenType::Star => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        TokenType::Percent => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+        TokenType::Bang => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+        TokenType::BangEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+        TokenType::EqualEqual => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+        TokenType::Greater => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        TokenType::GreaterEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison)
+        }
+        TokenType::Less => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+        TokenType::LessEqual => {
+            ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison)
+        }
+        TokenType::Identifier => ParseRule::new(Some(Compiler::variable), None, Precedence::None),
+        TokenType::String => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+        TokenType::Char => ParseRule::new(Some(Compiler::character), None, Precedence::None),
+        TokenType::Number => ParseRule::new(Some(Compiler::number), None, Precedence::None),
+        TokenType::And => ParseRule::new(None, Some(Compiler::and), Precedence::And),
+        TokenType::False => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        TokenType::Nil => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        TokenType::Or => ParseRule::new(None, Some(Compiler::or), Precedence::Or),
+        TokenType::Question => {
+            ParseRule::new(None, Some(Compiler::conditional), Precedence::Conditional)
+        }
+        TokenType::True => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+        _ => ParseRule::new(None, None, Precedence::None),
+    }
+}
+
+impl Compiler {
+    pub fn new(source: &str) -> Self {
+        Self {
+            parser: Parser::default(),
+            scanner: Scanner::new(source),
+            chunk: Chunk::new(),
+            kind: FunctionKind::Script,
+            locals: Vec::new(),
+            scope_depth: 0,
+            loops: Vec::new(),
+            last_number: None,
+            print_code: cfg!(feature = "debug_print_code"),
+        }
+    }
+
+    /// Turns printing finished chunks on or off, for callers that print the
+    /// disassembly themselves. See print_code.
+    pub fn set_print_code(&mut self, print: bool) {
+        self.print_code = print;
+    }
+
+    /// Compiles the source into a chunk, or returns None if there were any errors.
+    pub fn compile(&mut self) -> Option<Chunk> {
+        self.advance();
+        while !self.match_token(TokenType::Eof) {
+            self.declaration();
+        }
+        self.end_compiler("script");
+
+        if *self.parser.had_error.borrow() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.chunk))
+        }
+    }
+
+    /// Reports whether source fails to compile only because it ends too soon, as with an
+    /// unclosed block or a dangling operator, so that a REPL should read another line.
+    pub fn is_incomplete(source: &str) -> bool {
+        let mut compiler = Compiler::new(source);
+        compiler.parser.quiet = true;
+        compiler.compile();
+        let incomplete = *compiler.parser.incomplete.borrow();
+        incomplete
+    }
+
+    fn end_compiler(&mut self, name: &str) {
+        self.emit_return();
+
+        if self.print_code && !*self.parser.had_error.borrow() && !self.parser.quiet {
+            eprint!("{}", self.chunk.disassemble(name));
+        }
+    }
+
+    /// Starts compiling a new function, returning the state of the enclosing one.
+    fn begin_function(&mut self, kind: FunctionKind) -> FunctionState {
+        let enclosing = FunctionState {
+            chunk: mem::take(&mut self.chunk),
+            kind: mem::replace(&mut self.kind, kind),
+            locals: mem::take(&mut self.locals),
+            scope_depth: mem::replace(&mut self.scope_depth, 0),
+            loops: mem::take(&mut self.loops),
+        };
+        self.last_number = None;
+        // Slot 0 holds the function being called. The empty name can't be referenced.
+        self.locals.push(Local {
+            name: Token::default(),
+            depth: Some(0),
+        });
+        enclosing
+    }
+
+    /// Finishes the current function, returning its chunk and restoring the enclosing one.
+    fn end_function(&mut self, enclosing: FunctionState, name: &str) -> Chunk {
+        self.end_compiler(name);
+        self.kind = enclosing.kind;
+        self.locals = enclosing.locals;
+        self.scope_depth = enclosing.scope_depth;
+        self.loops = enclosing.loops;
+        self.last_number = None;
+        mem::replace(&mut self.chunk, enclosing.chunk)
+    }
+
+    /// Moves to the next token, reporting any error tokens the scanner hands back.
+    fn advance(&mut self) {
+        self.parser.previous = self.parser.current.clone();
+
+        loop {
+            self.parser.current = self.scanner.scan_token();
+            if self.parser.current.ttype != TokenType::Error {
+                break;
+            }
+            let message = self.parser.current.lexeme.clone();
+            self.error_at_current(&message);
+        }
+    }
+
+    /// Advances if the current token is of the given type, otherwise reports message.
+    fn consume(&mut self, ttype: TokenType, message: &str) {
+        if self.parser.current.ttype == ttype {
+            self.advance();
+            return;
+        }
+        self.error_at_current(message);
+    }
+
+    fn check(&self, ttype: TokenType) -> bool {
+        self.parser.current.ttype == ttype
+    }
+
+    /// Advances and returns true if the current token is of the given type.
+    fn match_token(&mut self, ttype: TokenType) -> bool {
+        if !self.check(ttype) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        self.chunk.write(byte, self.parser.previous.line);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        self.chunk.write_opcode(op, self.parser.previous.line);
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    /// Emits a jump with a placeholder operand and returns the operand's offset for patch_jump.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_op(instruction);
+        self.emit_bytes(0xff, 0xff);
+        self.chunk.count() - 2
+    }
+
+    /// Points the jump whose operand is at offset to the next instruction to be emitted.
+    fn patch_jump(&mut self, offset: usize) {
+        // Jump over the operand itself as well.
+        let jump = self.chunk.count() - offset - 2;
+        let Ok(jump) = u16::try_from(jump) else {
+            self.error("Too much code to jump over.");
+            return;
+        };
+
+        let [high, low] = jump.to_be_bytes();
+        self.chunk.patch(offset, high);
+        self.chunk.patch(offset + 1, low);
+        // The code before a jump target can't be rewritten without moving the target.
+        self.last_number = None;
+    }
+
+    /// Emits a backward jump to loop_start.
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+
+        // Jump back over the Loop instruction and its operand as well.
+        let offset = self.chunk.count() - loop_start + 2;
+        let Ok(offset) = u16::try_from(offset) else {
+            self.error("Loop body too large.");
+            self.emit_bytes(0, 0);
+            return;
+        };
+        let [high, low] = offset.to_be_bytes();
+        self.emit_bytes(high, low);
+    }
+
+    /// Emits the return at the end of a body. A function returns nil, while the script
+    /// simply stops.
+    fn emit_return(&mut self) {
+        if self.kind == FunctionKind::Function {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Return);
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        if self
+            .chunk
+            .write_constant(value, self.parser.previous.line)
+            .is_none()
+        {
+            self.error("Too many constants in one chunk.");
+        }
+    }
+
+    /// Emits a number constant, remembering it so an operator that follows can fold it.
+    fn emit_number(&mut self, value: f64) {
+        let start = self.chunk.count();
+        let constants = self.chunk.constants().len();
+        self.emit_constant(Value::Number(value));
+        self.last_number = Some(NumberLiteral {
+            value,
+            start,
+            end: self.chunk.count(),
+            constants,
+        });
+    }
+
+    /// Returns the number literal that is the last code emitted, if there is one.
+    fn trailing_number(&self) -> Option<NumberLiteral> {
+        self.last_number
+            .filter(|literal| literal.end == self.chunk.count())
+    }
+
+    /// Adds value to the constant pool. The operand is a single byte, so only 256 fit.
+    fn make_constant(&mut self, value: Value) -> u8 {
+        let constant = self.chunk.add_constants(value);
+        match u8::try_from(constant) {
+            Ok(constant) => constant,
+            Err(_) => {
+                self.error("Too many constants in one chunk.");
+                0
+            }
+        }
+    }
+
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if *self.parser.panic_mode.borrow() {
+            self.synchronize();
+        }
+    }
+
+    /// Skips tokens until a likely statement boundary, so one error doesn't cascade.
+    fn synchronize(&mut self) {
+        *self.parser.panic_mode.borrow_mut() = false;
+
+        while self.parser.current.ttype != TokenType::Eof {
+            if self.parser.previous.ttype == TokenType::Semicolon {
+                return;
+            }
+            match self.parser.current.ttype {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    /// Closes the innermost block, popping the locals declared in it.
+    fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+
+        let mut count = 0;
+        while let Some(local) = self.locals.last() {
+            if local.depth.is_some_and(|depth| depth <= self.scope_depth) {
+                break;
+            }
+            self.locals.pop();
+            count += 1;
+        }
+        self.emit_pops(count);
+    }
+
+    /// Emits the pops for count values, using PopN for more than one.
+    fn emit_pops(&mut self, mut count: usize) {
+        while count > 1 {
+            let batch = count.min(usize::from(u8::MAX));
+            self.emit_bytes(OpCode::PopN.into(), batch as u8);
+            count -= batch;
+        }
+        if count == 1 {
+            self.emit_op(OpCode::Pop);
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // The name is usable inside the body, so the function can call itself.
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
+
+    /// Compiles the parameters and body of a function and emits it as a constant.
+    fn function(&mut self, kind: FunctionKind) {
+        let name = self.parser.previous.lexeme.clone();
+        let enclosing = self.begin_function(kind);
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        let mut arity = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                arity += 1;
+                if arity > usize::from(u8::MAX) {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // No end_scope: the frame's slots are discarded when the function returns.
+        let chunk = self.end_function(enclosing, &name);
+        let function = Function {
+            arity,
+            chunk,
+            name: Some(name.into()),
+        };
+        self.emit_constant(Value::Function(Rc::new(function)));
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    /// Consumes a variable name. Returns the constant index of its name for a global,
+    /// or 0 for a local, which lives on the stack instead.
+    fn parse_variable(&mut self, message: &str) -> u8 {
+        self.consume(TokenType::Identifier, message);
+
+        self.declare_variable();
+        if self.scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.parser.previous.clone();
+        self.identifier_constant(&name)
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> u8 {
+        self.make_constant(Value::Str(name.lexeme.as_str().into()))
+    }
+
+    /// Adds the variable just named to the locals, unless it is a global.
+    fn declare_variable(&mut self) {
+        if self.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.parser.previous.clone();
+        let redeclared = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= self.scope_depth))
+            .any(|local| local.name.lexeme == name.lexeme);
+        if redeclared {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.locals.len() == LOCALS_MAX {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        self.locals.push(Local { name, depth: None });
+    }
+
+    /// Makes a declared variable available. A local's value is already in its slot.
+    fn define_variable(&mut self, global: u8) {
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_bytes(OpCode::DefineGlobal.into(), global);
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = Some(self.scope_depth);
+        }
+    }
+
+    /// Returns the stack slot of the innermost local with this name, if there is one.
+    fn resolve_local(&self, name: &Token) -> Option<u8> {
+        let slot = self
+            .locals
+            .iter()
+            .rposition(|local| local.name.lexeme == name.lexeme)?;
+        if self.locals[slot].depth.is_none() {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        // add_local keeps the count within a byte.
+        Some(slot as u8)
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_op(OpCode::Print);
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        // The condition stays on the stack, so each branch starts by popping it.
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement();
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_op(OpCode::Pop);
+        if self.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    /// Compiles `return expr;` or a bare `return;`, which returns nil.
+    fn return_statement(&mut self) {
+        if self.kind == FunctionKind::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_op(OpCode::Return);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.chunk.count();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(OpCode::Pop);
+        self.end_loop();
+    }
+
+    /// Desugars `for (init; cond; incr) body` into jumps and a loop. Every clause is optional.
+    fn for_statement(&mut self) {
+        // A variable declared in the initializer is scoped to the loop.
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.chunk.count();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_op(OpCode::Pop);
+        }
+
+        // The increment comes before the body in the source but runs after it, so jump
+        // over it into the body, and have the body loop back to it.
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.chunk.count();
+            self.expression();
+            self.emit_op(OpCode::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_op(OpCode::Pop);
+        }
+        self.end_loop();
+        self.end_scope();
+    }
+
+    /// Starts a loop body, which continue returns to start from.
+    fn begin_loop(&mut self, start: usize) {
+        self.loops.push(Loop {
+            start,
+            scope_depth: self.scope_depth,
+            breaks: Vec::new(),
+        });
+    }
+
+    /// Ends the innermost loop, pointing its breaks at the next instruction to be emitted.
+    fn end_loop(&mut self) {
+        let finished = self.loops.pop().expect("end_loop without begin_loop");
+        for jump in finished.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    /// Counts the locals declared inside the innermost loop, which break and continue
+    /// must pop since they jump out of their scopes.
+    fn loop_locals(&self) -> usize {
+        let Some(innermost) = self.loops.last() else {
+            return 0;
+        };
+        self.locals
+            .iter()
+            .rev()
+            .take_while(|local| {
+                local
+                    .depth
+                    .is_none_or(|depth| depth > innermost.scope_depth)
+            })
+            .count()
+    }
+
+    fn break_statement(&mut self) {
+        if self.loops.is_empty() {
+            self.error("Can't use 'break' outside of a loop.");
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+
+        self.emit_pops(self.loop_locals());
+        let jump = self.emit_jump(OpCode::Jump);
+        if let Some(innermost) = self.loops.last_mut() {
+            innermost.breaks.push(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        if self.loops.is_empty() {
+            self.error("Can't use 'continue' outside of a loop.");
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+
+        if let Some(start) = self.loops.last().map(|innermost| innermost.start) {
+            self.emit_pops(self.loop_locals());
+            self.emit_loop(start);
+        }
+    }
+
+    /// Evaluates an expression for its side effects and discards the result.
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_op(OpCode::Pop);
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    /// Parses any expression at the given precedence level or higher.
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+        let Some(prefix_rule) = get_rule(self.parser.previous.ttype).prefix else {
+            self.error("Expect expression.");
+            return;
+        };
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule(self, can_assign);
+
+        while precedence <= get_rule(self.parser.current.ttype).precedence {
+            self.advance();
+            if let Some(infix_rule) = get_rule(self.parser.previous.ttype).infix {
+                infix_rule(self, can_assign);
+            }
+        }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let lexeme = self.parser.previous.lexeme.replace('_', "");
+        let radix = match lexeme.get(..2) {
+            Some("0x" | "0X") => Some(16),
+            Some("0b" | "0B") => Some(2),
+            _ => None,
+        };
+        let value = match radix {
+            Some(radix) => match u64::from_str_radix(&lexeme[2..], radix) {
+                Ok(value) => value as f64,
+                Err(_) => {
+                    self.error("Integer literal is too large.");
+                    return;
+                }
+            },
+            None => lexeme.parse().unwrap(),
+        };
+        self.emit_number(value);
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        // Trim the surrounding quotes.
+        let lexeme = &self.parser.previous.lexeme;
+        let value = unescape(&lexeme[1..lexeme.len() - 1]);
+        self.emit_constant(Value::Str(value.into()));
+    }
+
+    /// Compiles a character literal to the number of its code point.
+    fn character(&mut self, _can_assign: bool) {
+        // Trim the surrounding quotes. The scanner has checked there's exactly one character.
+        let lexeme = &self.parser.previous.lexeme;
+        let c = unescape(&lexeme[1..lexeme.len() - 1])
+            .chars()
+            .next()
+            .unwrap();
+        self.emit_number(f64::from(u32::from(c)));
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.parser.previous.clone();
+        self.named_variable(&name, can_assign);
+    }
+
+    /// Emits a read of the named variable, or a write if it is followed by '='.
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        let (get_op, set_op, arg) = match self.resolve_local(name) {
+            Some(slot) => (OpCode::GetLocal, OpCode::SetLocal, slot),
+            None => {
+                let arg = self.identifier_constant(name);
+                (OpCode::GetGlobal, OpCode::SetGlobal, arg)
+            }
+        };
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_bytes(set_op.into(), arg);
+        } else {
+            self.emit_bytes(get_op.into(), arg);
+        }
+    }
+
+    /// Skips the right operand when the left one is falsey, leaving the left as the result.
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    /// Skips the right operand when the left one is truthy, leaving the left as the result.
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    /// Compiles `cond ? a : b` like an if statement, leaving the chosen branch's value.
+    /// It groups to the right, so `a ? b : c ? d : e` means `a ? b : (c ? d : e)`.
+    fn conditional(&mut self, _can_assign: bool) {
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::Conditional);
+        self.consume(
+            TokenType::Colon,
+            "Expect ':' after then branch of conditional expression.",
+        );
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::Conditional);
+        self.patch_jump(else_jump);
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.parser.previous.ttype {
+            TokenType::False => self.emit_op(OpCode::False),
+            TokenType::Nil => self.emit_op(OpCode::Nil),
+            TokenType::True => self.emit_op(OpCode::True),
+            ttype => unreachable!("literal() called for {}", ttype),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::Call.into(), arg_count);
+    }
+
+    /// Compiles the arguments of a call up to the closing ')' and returns how many there were.
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: u8 = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count = arg_count.saturating_add(1);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator_type = self.parser.previous.ttype;
+
+        // Compile the operand.
+        self.parse_precedence(Precedence::Unary);
+
+        match operator_type {
+            TokenType::Bang => self.emit_op(OpCode::Not),
+            TokenType::Minus => self.emit_op(OpCode::Negate),
+            _ => unreachable!("unary() called for {}", operator_type),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator_type = self.parser.previous.ttype;
+        let left = self.trailing_number();
+        let rule = get_rule(operator_type);
+        self.parse_precedence(rule.precedence.next());
+
+        if let (Some(left), Some(right)) = (left, self.trailing_number()) {
+            // Both operands are literals only if nothing was emitted between them.
+            if right.start == left.end && self.fold(operator_type, left, right) {
+                return;
+            }
+        }
+
+        match operator_type {
+            TokenType::BangEqual => self.emit_bytes(OpCode::Equal.into(), OpCode::Not.into()),
+            TokenType::EqualEqual => self.emit_op(OpCode::Equal),
+            TokenType::Greater => self.emit_op(OpCode::Greater),
+            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less.into(), OpCode::Not.into()),
+            TokenType::Less => self.emit_op(OpCode::Less),
+            TokenType::LessEqual => self.emit_bytes(OpCode::Greater.into(), OpCode::Not.into()),
+            TokenType::Plus => self.emit_op(OpCode::Add),
+            TokenType::Minus => self.emit_op(OpCode::Subtract),
+            TokenType::Star => self.emit_op(OpCode::Multiply),
+            TokenType::Slash => self.emit_op(OpCode::Divide),
+            TokenType::Percent => self.emit_op(OpCode::Modulo),
+            _ => unreachable!("binary() called for {}", operator_type),
+        }
+    }
+
+    /// Replaces the code for two number literals with one for the result of applying
+    /// operator to them. Returns false, changing nothing, if the result isn't known
+    /// until runtime.
+    fn fold(&mut self, operator: TokenType, left: NumberLiteral, right: NumberLiteral) -> bool {
+        let (a, b) = (left.value, right.value);
+        let value = match operator {
+            TokenType::Plus => a + b,
+            TokenType::Minus => a - b,
+            TokenType::Star => a * b,
+            // Dividing by zero may be a runtime error, depending on how the VM is set up.
+            TokenType::Slash if b != 0.0 => a / b,
+            TokenType::Percent if b != 0.0 => a % b,
+            _ => return false,
+        };
+
+        // Only the two literals used the constants added since the left one was emitted.
+        self.chunk.truncate(left.start, left.constants);
+        self.emit_number(value);
+        true
+    }
+
+    fn error_at_current(&self, message: &str) {
+        self.error_at(&self.parser.current, message);
+    }
+
+    fn error(&self, message: &str) {
+        self.error_at(&self.parser.previous, message);
+    }
+
+    fn error_at(&self, token: &Token, message: &str) {
+        if *self.parser.panic_mode.borrow() {
+            return;
+        }
+        *self.parser.panic_mode.borrow_mut() = true;
+
+        let location = match token.ttype {
+            TokenType::Eof => " at end".to_string(),
+            TokenType::Error => String::new(),
+            _ => format!(" at '{}'", token.lexeme),
+        };
+        let error = format!("[line {}] Error{}: {}", token.line, location, message);
+        if !self.parser.quiet {
+            eprintln!("{}", error);
+        }
+
+        if !*self.parser.had_error.borrow() {
+            *self.parser.incomplete.borrow_mut() = token.ttype == TokenType::Eof;
+        }
+        self.parser.errors.borrow_mut().push(error);
+        *self.parser.had_error.borrow_mut() = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Walks a compiled chunk up to its final OP_RETURN, collecting opcodes and constants.
+    fn instructions(chunk: &Chunk) -> Vec<(OpCode, Option<Value>)> {
+        let mut out = Vec::new();
+        let mut offset = 0;
+        loop {
+            let op = OpCode::try_from(chunk.read(offset).unwrap()).unwrap();
+            match op {
+                OpCode::Constant | OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal => {
+                    let index = chunk.read(offset + 1).unwrap() as usize;
+                    out.push((op, Some(chunk.get_constant(index))));
+                    offset += 2;
+                }
+                OpCode::GetLocal | OpCode::SetLocal | OpCode::PopN | OpCode::Call => {
+                    out.push((op, None));
+                    offset += 2;
+                }
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                    out.push((op, None));
+                    offset += 3;
+                }
+                _ => {
+                    out.push((op, None));
+                    offset += 1;
+                }
+            }
+            if op == OpCode::Return {
+                return out;
+            }
+        }
+    }
+
+    /// Compiles source as an expression statement and returns the expression's instructions.
+    fn compile_expression(source: &str) -> Vec<(OpCode, Option<Value>)> {
+        let chunk = Compiler::new(&format!("{};", source)).compile().unwrap();
+        let mut ops = instructions(&chunk);
+        let tail = ops.split_off(ops.len() - 2);
+        assert_eq!(tail, vec![(OpCode::Pop, None), (OpCode::Return, None)]);
+        ops
+    }
+
+    /// Like compile_expression, but only keeps the opcodes.
+    fn compile_opcodes(source: &str) -> Vec<OpCode> {
+        compile_expression(source)
+            .into_iter()
+            .map(|(op, _)| op)
+            .collect()
+    }
+
+    #[test]
+    fn consume_reports_a_mismatch() {
+        let mut compiler = Compiler::new("print");
+        compiler.advance();
+
+        compiler.consume(TokenType::Print, "Expect 'print'.");
+        assert!(!*compiler.parser.had_error.borrow());
+
+        compiler.consume(TokenType::Semicolon, "Expect ';'.");
+        assert!(*compiler.parser.had_error.borrow());
+    }
+
+    #[test]
+    fn compiles_an_empty_program() {
+        let chunk = Compiler::new("").compile().unwrap();
+        assert_eq!(instructions(&chunk), vec![(OpCode::Return, None)]);
+    }
+
+    #[test]
+    fn compiles_arithmetic_with_precedence() {
+        assert_eq!(
+            compile_expression("(-1 + 2) * 3 - -4"),
+            vec![
+                (OpCode::Constant, Some(Value::Number(1.0))),
+                (OpCode::Negate, None),
+                (OpCode::Constant, Some(Value::Number(2.0))),
+                (OpCode::Add, None),
+                (OpCode::Constant, Some(Value::Number(3.0))),
+                (OpCode::Multiply, None),
+                (OpCode::Constant, Some(Value::Number(4.0))),
+                (OpCode::Negate, None),
+                (OpCode::Subtract, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn modulo_binds_like_multiplication() {
+        assert_eq!(
+            compile_opcodes("a + b % c"),
+            vec![
+                OpCode::GetGlobal,
+                OpCode::GetGlobal,
+                OpCode::GetGlobal,
+                OpCode::Modulo,
+                OpCode::Add,
+            ]
+        );
+        assert_eq!(
+            compile_opcodes("a % b * c")[2..],
+            [OpCode::Modulo, OpCode::GetGlobal, OpCode::Multiply]
+        );
+    }
+
+    #[test]
+    fn binary_operators_are_left_associative() {
+        assert_eq!(
+            compile_expression("a / b / c"),
+            vec![
+                (OpCode::GetGlobal, Some(Value::Str("a".into()))),
+                (OpCode::GetGlobal, Some(Value::Str("b".into()))),
+                (OpCode::Divide, None),
+                (OpCode::GetGlobal, Some(Value::Str("c".into()))),
+                (OpCode::Divide, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn folds_literal_arithmetic() {
+        let cases = [
+            ("1 + 2", 3.0),
+            ("2 * 3 + 4", 10.0),
+            ("2 + 3 * 4", 14.0),
+            ("8 / 4 / 2", 1.0),
+            ("(1 + 2) * 3", 9.0),
+            ("7 % 3 - 'a'", -96.0),
+        ];
+        for (source, value) in cases {
+            let chunk = Compiler::new(&format!("{};", source)).compile().unwrap();
+            assert_eq!(
+                instructions(&chunk)[0],
+                (OpCode::Constant, Some(Value::Number(value))),
+                "{}",
+                source
+            );
+            // The operands' constants are dropped along with their code.
+            assert_eq!(chunk.constants(), [Value::Number(value)], "{}", source);
+        }
+    }
+
+    #[test]
+    fn only_folds_operands_known_at_compile_time() {
+        assert_eq!(
+            compile_opcodes("1 + 2 * x"),
+            vec![
+                OpCode::Constant,
+                OpCode::Constant,
+                OpCode::GetGlobal,
+                OpCode::Multiply,
+                OpCode::Add,
+            ]
+        );
+        assert_eq!(
+            compile_opcodes("f() + 1 * 2"),
+            vec![
+                OpCode::GetGlobal,
+                OpCode::Call,
+                OpCode::Constant,
+                OpCode::Add,
+            ]
+        );
+        // The jump lands just past the 1, so it can't be merged with the 2.
+        assert_eq!(
+            compile_opcodes("(x and 1) + 2"),
+            vec![
+                OpCode::GetGlobal,
+                OpCode::JumpIfFalse,
+                OpCode::Pop,
+                OpCode::Constant,
+                OpCode::Constant,
+                OpCode::Add,
+            ]
+        );
+        // Whether dividing by zero is an error is up to the VM.
+        assert_eq!(compile_opcodes("1 / 0")[2..], [OpCode::Divide]);
+        assert_eq!(compile_opcodes("1 % 0")[2..], [OpCode::Modulo]);
+        assert_eq!(compile_opcodes("1 < 2")[2..], [OpCode::Less]);
+    }
+
+    #[test]
+    fn compiles_other_number_forms() {
+        assert_eq!(
+            compile_expression("0xFF"),
+            vec![(OpCode::Constant, Some(Value::Number(255.0)))]
+        );
+        assert_eq!(
+            compile_expression("0b1010"),
+            vec![(OpCode::Constant, Some(Value::Number(10.0)))]
+        );
+        assert_eq!(
+            compile_expression("1_000.5_5"),
+            vec![(OpCode::Constant, Some(Value::Number(1000.55)))]
+        );
+        assert!(Compiler::new("0x10000000000000000;").compile().is_none());
+    }
+
+    #[test]
+    fn repeated_literals_share_a_constant() {
+        let chunk = Compiler::new("print 1; print 1; print 1;")
+            .compile()
+            .unwrap();
+        let operands = [
+            chunk.read(1).unwrap(),
+            chunk.read(4).unwrap(),
+            chunk.read(7).unwrap(),
+        ];
+        assert_eq!(operands, [0, 0, 0]);
+
+        let chunk = Compiler::new("var x = \"x\"; x = x;").compile().unwrap();
+        assert_eq!(chunk.get_constant(0), Value::Str("x".into()));
+        assert_eq!(chunk.read(3).unwrap(), 0);
+    }
+
+    #[test]
+    fn compiles_literals_to_their_opcodes() {
+        assert_eq!(compile_opcodes("true"), vec![OpCode::True]);
+        assert_eq!(compile_opcodes("nil"), vec![OpCode::Nil]);
+        assert_eq!(compile_opcodes("false"), vec![OpCode::False]);
+    }
+
+    #[test]
+    fn compiles_string_constants() {
+        assert_eq!(
+            compile_expression(r#""a\tb" + "c""#),
+            vec![
+                (OpCode::Constant, Some(Value::Str("a\tb".into()))),
+                (OpCode::Constant, Some(Value::Str("c".into()))),
+                (OpCode::Add, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn compiles_char_literals_to_code_points() {
+        for (source, code_point) in [("'a'", 97.0), (r"'\n'", 10.0), ("'é'", 233.0)] {
+            assert_eq!(
+                compile_expression(source),
+                vec![(OpCode::Constant, Some(Value::Number(code_point)))],
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_char_literals_are_errors() {
+        for (source, error) in [
+            ("'';", "[line 1] Error: Empty character literal."),
+            ("'ab';", "[line 1] Error: Character literal too long."),
+        ] {
+            let mut compiler = Compiler::new(source);
+            assert!(compiler.compile().is_none());
+            assert_eq!(compiler.parser.errors.borrow()[0], error);
+        }
+    }
+
+    #[test]
+    fn compiles_logical_not() {
+        assert_eq!(
+            compile_opcodes("!!nil"),
+            vec![OpCode::Nil, OpCode::Not, OpCode::Not]
+        );
+    }
+
+    #[test]
+    fn compiles_conditionals_like_if() {
+        assert_eq!(
+            compile_opcodes("a ? b : c"),
+            vec![
+                OpCode::GetGlobal,
+                OpCode::JumpIfFalse,
+                OpCode::Pop,
+                OpCode::GetGlobal,
+                OpCode::Jump,
+                OpCode::Pop,
+                OpCode::GetGlobal,
+            ]
+        );
+        // Binds looser than or and groups to the right.
+        assert_eq!(
+            compile_opcodes("a or b ? c : d ? e : f")[..3],
+            [OpCode::GetGlobal, OpCode::JumpIfFalse, OpCode::Jump]
+        );
+        assert_eq!(compile_opcodes("a ? b : c ? d : e").len(), 13);
+    }
+
+    #[test]
+    fn conditional_without_colon_is_an_error() {
+        let mut compiler = Compiler::new("print a ? b;");
+        assert!(compiler.compile().is_none());
+        assert_eq!(
+            *compiler.parser.errors.borrow(),
+            vec!["[line 1] Error at ';': Expect ':' after then branch of conditional expression."]
+        );
+    }
+
+    #[test]
+    fn break_and_continue_pop_the_loop_body_locals() {
+        let chunk = Compiler::new("for (var i = 0; i; i) { var a; { var b; break; } }")
+            .compile()
+            .unwrap();
+        // Only a and b are popped. The jump lands on the pop of i, which belongs to the loop.
+        let disassembly = chunk.disassemble("loop");
+        assert!(
+            disassembly.contains(
+                "0019    | OP_POPN             2\n\
+                 0021    | OP_JUMP            21 -> 30\n"
+            ),
+            "{}",
+            disassembly
+        );
+    }
+
+    #[test]
+    fn break_and_continue_outside_a_loop_are_errors() {
+        for (source, error) in [
+            (
+                "break;",
+                "[line 1] Error at 'break': Can't use 'break' outside of a loop.",
+            ),
+            (
+                "{ continue; }",
+                "[line 1] Error at 'continue': Can't use 'continue' outside of a loop.",
+            ),
+            (
+                "while (true) { fun f() { break; } }",
+                "[line 1] Error at 'break': Can't use 'break' outside of a loop.",
+            ),
+            (
+                "while (true) break",
+                "[line 1] Error at end: Expect ';' after 'break'.",
+            ),
+        ] {
+            let mut compiler = Compiler::new(source);
+            assert!(compiler.compile().is_none(), "{}", source);
+            assert_eq!(*compiler.parser.errors.borrow(), vec![error]);
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            compile_opcodes("nil or true and false"),
+            vec![
+                OpCode::Nil,
+                OpCode::JumpIfFalse,
+                OpCode::Jump,
+                OpCode::Pop,
+                OpCode::True,
+                OpCode::JumpIfFalse,
+                OpCode::Pop,
+                OpCode::False,
+            ]
+        );
+    }
+
+    #[test]
+    fn compiles_functions_into_constants() {
+        let chunk = Compiler::new("fun add(a, b) { print a + b; }")
+            .compile()
+            .unwrap();
+        let ops = instructions(&chunk);
+        assert_eq!(ops[1].0, OpCode::DefineGlobal);
+        let Some(Value::Function(add)) = &ops[0].1 else {
+            panic!("expected a function constant, found {:?}", ops[0]);
+        };
+        assert_eq!((add.arity, add.name.as_deref()), (2, Some("add")));
+
+        // Slot 0 is the function itself, so the parameters start at 1.
+        assert_eq!(
+            instructions(&add.chunk),
+            vec![
+                (OpCode::GetLocal, None),
+                (OpCode::GetLocal, None),
+                (OpCode::Add, None),
+                (OpCode::Print, None),
+                (OpCode::Nil, None),
+                (OpCode::Return, None),
+            ]
+        );
+        assert_eq!(
+            (add.chunk.read(1).unwrap(), add.chunk.read(3).unwrap()),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn compiles_return_statements() {
+        let chunk = Compiler::new("fun f(a) { if (a) return; return a; }")
+            .compile()
+            .unwrap();
+        let Value::Function(f) = chunk.get_constant(1) else {
+            panic!("expected f to be constant 1");
+        };
+        let ops: Vec<_> = instructions(&f.chunk)
+            .into_iter()
+            .map(|(op, _)| op)
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::GetLocal,
+                OpCode::JumpIfFalse,
+                OpCode::Pop,
+                // The bare return returns nil.
+                OpCode::Nil,
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn returning_from_top_level_code_is_an_error() {
+        let mut compiler = Compiler::new("return 1;");
+        assert!(compiler.compile().is_none());
+        assert_eq!(
+            *compiler.parser.errors.borrow(),
+            vec!["[line 1] Error at 'return': Can't return from top-level code."]
+        );
+    }
+
+    #[test]
+    fn malformed_functions_are_errors() {
+        for (source, error) in [
+            ("fun () {}", "[line 1] Error at '(': Expect function name."),
+            (
+                "fun f(1) {}",
+                "[line 1] Error at '1': Expect parameter name.",
+            ),
+            (
+                "fun f(a b) {}",
+                "[line 1] Error at 'b': Expect ')' after parameters.",
+            ),
+            (
+                "fun f() print 1;",
+                "[line 1] Error at 'print': Expect '{' before function body.",
+            ),
+        ] {
+            let mut compiler = Compiler::new(source);
+            assert!(compiler.compile().is_none(), "{}", source);
+            assert_eq!(compiler.parser.errors.borrow()[0], error);
+        }
+    }
+
+    #[test]
+    fn compiles_calls() {
+        let ops = compile_expression("f(1, g())(2)");
+        let ops: Vec<_> = ops.into_iter().map(|(op, _)| op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::GetGlobal,
+                OpCode::Constant,
+                OpCode::GetGlobal,
+                OpCode::Call,
+                OpCode::Call,
+                OpCode::Constant,
+                OpCode::Call,
+            ]
+        );
+        let chunk = Compiler::new("f(1, g())(2);").compile().unwrap();
+        // The argument counts: g(), f(1, g()) and then (2).
+        assert_eq!(
+            [
+                chunk.read(7).unwrap(),
+                chunk.read(9).unwrap(),
+                chunk.read(13).unwrap()
+            ],
+            [0, 2, 1]
+        );
+    }
+
+    #[test]
+    fn too_many_arguments_is_an_error() {
+        let args = vec!["nil"; 256].join(", ");
+        let mut compiler = Compiler::new(&format!("f({});", args));
+        assert!(compiler.compile().is_none());
+        assert_eq!(
+            *compiler.parser.errors.borrow(),
+            vec!["[line 1] Error at 'nil': Can't have more than 255 arguments."]
+        );
+    }
+
+    #[test]
+    fn compiles_comparisons() {
+        let cases = [
+            ("1 == 2", vec![OpCode::Equal]),
+            ("1 != 2", vec![OpCode::Equal, OpCode::Not]),
+            ("1 > 2", vec![OpCode::Greater]),
+            ("1 >= 2", vec![OpCode::Less, OpCode::Not]),
+            ("1 < 2", vec![OpCode::Less]),
+            ("1 <= 2", vec![OpCode::Greater, OpCode::Not]),
+        ];
+        for (source, ops) in cases {
+            assert_eq!(compile_opcodes(source)[2..], ops, "{}", source);
+        }
+    }
+
+    #[test]
+    fn comparison_binds_looser_than_arithmetic() {
+        assert_eq!(
+            compile_opcodes("a + b < c == true"),
+            vec![
+                OpCode::GetGlobal,
+                OpCode::GetGlobal,
+                OpCode::Add,
+                OpCode::GetGlobal,
+                OpCode::Less,
+                OpCode::True,
+                OpCode::Equal,
+            ]
+        );
+    }
+
+    #[test]
+    fn compiles_print_and_expression_statements() {
+        let chunk = Compiler::new("print 1; 2;").compile().unwrap();
+        let ops: Vec<_> = instructions(&chunk).into_iter().map(|(op, _)| op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::Constant,
+                OpCode::Print,
+                OpCode::Constant,
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn compiles_global_variables() {
+        let chunk = Compiler::new("var x = 1; x = x;").compile().unwrap();
+        let name = Some(Value::Str("x".into()));
+        assert_eq!(
+            instructions(&chunk),
+            vec![
+                (OpCode::Constant, Some(Value::Number(1.0))),
+                (OpCode::DefineGlobal, name.clone()),
+                (OpCode::GetGlobal, name.clone()),
+                (OpCode::SetGlobal, name),
+                (OpCode::Pop, None),
+                (OpCode::Return, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn uninitialized_variables_are_nil() {
+        let chunk = Compiler::new("var x;").compile().unwrap();
+        assert_eq!(instructions(&chunk)[0], (OpCode::Nil, None));
+    }
+
+    #[test]
+    fn compiles_if_else() {
+        let chunk = Compiler::new("if (true) print 1; else print 2;")
+            .compile()
+            .unwrap();
+        let ops: Vec<_> = instructions(&chunk).into_iter().map(|(op, _)| op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::True,
+                OpCode::JumpIfFalse,
+                OpCode::Pop,
+                OpCode::Constant,
+                OpCode::Print,
+                OpCode::Jump,
+                OpCode::Pop,
+                OpCode::Constant,
+                OpCode::Print,
+                OpCode::Return,
+            ]
+        );
+        // The condition jumps past the then branch and its trailing jump.
+        assert_eq!((chunk.read(2).unwrap(), chunk.read(3).unwrap()), (0, 7));
+        assert_eq!((chunk.read(9).unwrap(), chunk.read(10).unwrap()), (0, 4));
+    }
+
+    #[test]
+    fn jumping_over_too_much_code_is_an_error() {
+        let body = vec!["nil"; 40_000].join(" == ");
+        let mut compiler = Compiler::new(&format!("if (true) {};", body));
+        assert!(compiler.compile().is_none());
+        assert_eq!(
+            *compiler.parser.errors.borrow(),
+            vec!["[line 1] Error at ';': Too much code to jump over."]
+        );
+    }
+
+    #[test]
+    fn compiles_while_loops() {
+        let chunk = Compiler::new("while (false) 1;").compile().unwrap();
+        let ops: Vec<_> = instructions(&chunk).into_iter().map(|(op, _)| op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::False,
+                OpCode::JumpIfFalse,
+                OpCode::Pop,
+                OpCode::Constant,
+                OpCode::Pop,
+                OpCode::Loop,
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+        // The loop lands back on the condition at offset 0.
+        assert_eq!((chunk.read(9).unwrap(), chunk.read(10).unwrap()), (0, 11));
+    }
+
+    #[test]
+    fn for_clauses_are_optional() {
+        let chunk = Compiler::new("for (;;) print 1;").compile().unwrap();
+        let ops: Vec<_> = instructions(&chunk).into_iter().map(|(op, _)| op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::Constant,
+                OpCode::Print,
+                OpCode::Loop,
+                OpCode::Return
+            ]
+        );
+        assert_eq!((chunk.read(4).unwrap(), chunk.read(5).unwrap()), (0, 6));
+
+        assert!(Compiler::new("for (var i = 0; i < 1) print i;")
+            .compile()
+            .is_none());
+    }
+
+    #[test]
+    fn looping_over_too_much_code_is_an_error() {
+        let body = vec!["nil"; 40_000].join(" == ");
+        let mut compiler = Compiler::new(&format!("while (false) {};", body));
+        assert!(compiler.compile().is_none());
+        assert_eq!(
+            *compiler.parser.errors.borrow(),
+            vec!["[line 1] Error at ';': Loop body too large."]
+        );
+    }
+
+    #[test]
+    fn compiles_locals_to_stack_slots() {
+        let chunk = Compiler::new("{ var a = 1; { var b = 2; b = a; } a; }")
+            .compile()
+            .unwrap();
+        let ops: Vec<_> = instructions(&chunk).into_iter().map(|(op, _)| op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::Constant,
+                OpCode::Constant,
+                OpCode::GetLocal,
+                OpCode::SetLocal,
+                OpCode::Pop,
+                OpCode::Pop,
+                OpCode::GetLocal,
+                OpCode::Pop,
+                OpCode::Pop,
+                OpCode::Return,
+            ]
+        );
+        // a is in slot 0 and b in slot 1.
+        assert_eq!(chunk.read(5).unwrap(), 0);
+        assert_eq!(chunk.read(7).unwrap(), 1);
+        assert_eq!(chunk.read(11).unwrap(), 0);
+    }
+
+    #[test]
+    fn pops_a_block_of_locals_at_once() {
+        let chunk = Compiler::new("{ var a; var b; var c; }").compile().unwrap();
+        let ops: Vec<_> = instructions(&chunk).into_iter().map(|(op, _)| op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::Nil,
+                OpCode::Nil,
+                OpCode::Nil,
+                OpCode::PopN,
+                OpCode::Return
+            ]
+        );
+        assert_eq!(chunk.read(4).unwrap(), 3);
+    }
+
+    #[test]
+    fn pops_more_locals_than_one_popn_can_hold() {
+        let declarations: String = (0..256).map(|n| format!("var v{}; ", n)).collect();
+        let chunk = Compiler::new(&format!("{{ {}}}", declarations))
+            .compile()
+            .unwrap();
+        let ops = instructions(&chunk);
+        assert_eq!(
+            ops[256..],
+            [
+                (OpCode::PopN, None),
+                (OpCode::Pop, None),
+                (OpCode::Return, None)
+            ]
+        );
+        assert_eq!(chunk.read(257).unwrap(), 255);
+    }
+
+    #[test]
+    fn reading_a_local_in_its_own_initializer_is_an_error() {
+        let mut compiler = Compiler::new("{ var a = a; }");
+        assert!(compiler.compile().is_none());
+        assert_eq!(
+            *compiler.parser.errors.borrow(),
+            vec!["[line 1] Error at 'a': Can't read local variable in its own initializer."]
+        );
+
+        // A global of the same name doesn't help.
+        assert!(Compiler::new("var a = 1; { var a = a; }")
+            .compile()
+            .is_none());
+    }
+
+    #[test]
+    fn redeclaring_a_local_in_the_same_scope_is_an_error() {
+        let mut compiler = Compiler::new("{ var a = 1; var a = 2; }");
+        assert!(compiler.compile().is_none());
+        assert_eq!(
+            *compiler.parser.errors.borrow(),
+            vec!["[line 1] Error at 'a': Already a variable with this name in this scope."]
+        );
+
+        assert!(Compiler::new("{ var a = 1; { var a = 2; } }")
+            .compile()
+            .is_some());
+        assert!(Compiler::new("var a = 1; var a = 2;").compile().is_some());
+    }
+
+    #[test]
+    fn unclosed_block_is_an_error() {
+        assert!(Compiler::new("{ print 1;").compile().is_none());
+    }
+
+    #[test]
+    fn invalid_assignment_target_is_an_error() {
+        assert!(Compiler::new("var a; var b; a + b = 1;")
+            .compile()
+            .is_none());
+        assert!(Compiler::new("1 = 2;").compile().is_none());
+    }
+
+    #[test]
+    fn reports_each_error_once_and_recovers() {
+        let mut compiler = Compiler::new("print 1 +;\nvar = 2;\nprint 3;");
+        assert!(compiler.compile().is_none());
+        assert_eq!(
+            *compiler.parser.errors.borrow(),
+            vec![
+                "[line 1] Error at ';': Expect expression.",
+                "[line 2] Error at '=': Expect variable name.",
+            ]
+        );
+    }
+
+    #[test]
+    fn too_many_constants_is_reported_once() {
+        let mut compiler = Compiler::new(&sum_of_constants(300));
+        assert!(compiler.compile().is_none());
+        assert_eq!(compiler.parser.errors.borrow().len(), 1);
+    }
+
+    #[test]
+    fn statements_need_a_semicolon() {
+        assert!(Compiler::new("print 1").compile().is_none());
+        assert!(Compiler::new("1 + 2").compile().is_none());
+    }
+
+    /// Builds `-0 + -1 + ... + -(count - 1);`, which needs count distinct constants.
+    /// Negating each one keeps the sum from being folded.
+    fn sum_of_constants(count: usize) -> String {
+        let sum = (0..count)
+            .map(|n| format!("-{}", n))
+            .collect::<Vec<_>>()
+            .join(" + ");
+        format!("{};", sum)
+    }
+
+    #[test]
+    fn fills_the_constant_pool() {
+        let chunk = Compiler::new(&sum_of_constants(256)).compile().unwrap();
+        assert_eq!(chunk.get_constant(255), Value::Number(255.0));
+    }
+
+    #[test]
+    fn too_many_constants_is_an_error() {
+        assert!(Compiler::new(&sum_of_constants(300)).compile().is_none());
+    }
+
+    #[test]
+    fn tells_incomplete_input_from_invalid_input() {
+        for source in ["print (1 +", "{ var a = 1;", "if (true)", "print 1"] {
+            assert!(Compiler::is_incomplete(source), "{}", source);
+        }
+        for source in ["print (1 + 2);", "print 1 +;", ")", "{ print 1; } }", ""] {
+            assert!(!Compiler::is_incomplete(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn unbalanced_grouping_is_an_error() {
+        assert!(Compiler::new("(1 + 2;").compile().is_none());
+        assert!(Compiler::new("1 +;").compile().is_none());
+    }
+}
@@ -0,0 +1,6 @@
+pub mod chunk;
+pub mod compiler;
+pub mod scanner;
+pub mod token;
+pub mod value;
+pub mod vm;
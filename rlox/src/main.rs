@@ -1,3 +1,163 @@
+use std::io::{self, BufRead, Write};
+use std::{env, fs, process};
+
+use rlox::chunk::Chunk;
+use rlox::compiler::Compiler;
+use rlox::scanner::Scanner;
+use rlox::value::Value;
+use rlox::vm::{InterpretError, VM};
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().collect();
+
+    match args.as_slice() {
+        [_] => run_prompt(&mut VM::new()),
+        [_, flag, path] if flag == "--dump-tokens" => dump_tokens(path),
+        [_, flag, path] if flag == "--dump-bytecode" => dump_bytecode(path),
+        // The dump flags need a script, and a lone flag isn't a path either.
+        [_, flag] if flag == "--dump-tokens" || flag == "--dump-bytecode" => usage(),
+        [_, path] => run_file(&mut VM::new(), path),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: rlox [--dump-tokens | --dump-bytecode] script | rlox [script]");
+    process::exit(64);
+}
+
+/// The commands the REPL handles itself, with their descriptions for :help.
+const COMMANDS: [(&str, &str); 4] = [
+    (":help", "List these commands"),
+    (":quit", "Exit the REPL"),
+    (":dump", "Disassemble the last entry that compiled"),
+    (":reset", "Forget all global variables"),
+];
+
+/// Reads and runs one entry at a time. An entry that stops short, like an unclosed block,
+/// keeps reading lines at a `...` prompt until it is complete or a blank line is entered.
+/// A line starting with `:` is a command from COMMANDS rather than Lox.
+fn run_prompt(vm: &mut VM) {
+    let stdin = io::stdin();
+    let mut source = String::new();
+    let mut line = String::new();
+    let mut last_compiled = None;
+    loop {
+        print!("{}", if source.is_empty() { "> " } else { "... " });
+        io::stdout().flush().unwrap();
+
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => {
+                println!();
+                break;
+            }
+            Ok(_) => {}
+            // The bad line has been read past, so carry on with the next one.
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("Could not read input: {}", err);
+                continue;
+            }
+            Err(err) => {
+                eprintln!("Could not read input: {}", err);
+                break;
+            }
+        }
+        if source.is_empty() && line.starts_with(':') {
+            if !run_command(vm, line.trim_end(), last_compiled.as_deref()) {
+                break;
+            }
+            continue;
+        }
+        source.push_str(&line);
+        if !line.trim().is_empty() && Compiler::is_incomplete(&source) {
+            continue;
+        }
+
+        // The error has already been reported, and the REPL carries on regardless.
+        if vm.interpret(&source) != Err(InterpretError::CompileError) {
+            last_compiled = Some(source.clone());
+        }
+        source.clear();
+    }
+}
+
+/// Runs a REPL command. Returns false if the REPL should exit.
+fn run_command(vm: &mut VM, command: &str, last_compiled: Option<&str>) -> bool {
+    match command {
+        ":help" => {
+            for (name, description) in COMMANDS {
+                println!("{:<8} {}", name, description);
+            }
+        }
+        ":quit" => return false,
+        ":dump" => match last_compiled {
+            // It compiled before, and compiling is deterministic.
+            Some(source) => match compile_for_dump(source) {
+                Some(chunk) => print_disassembly(&chunk, "script"),
+                None => unreachable!("the last entry compiled before"),
+            },
+            None => eprintln!("Nothing has been compiled yet."),
+        },
+        ":reset" => vm.reset_globals(),
+        _ => eprintln!("Unknown command '{}'. Type :help for a list.", command),
+    }
+    true
+}
+
+fn run_file(vm: &mut VM, path: &str) {
+    let source = read_source(path);
+    match vm.interpret(&source) {
+        Ok(()) => {}
+        Err(InterpretError::CompileError) => process::exit(65),
+        Err(InterpretError::RuntimeError) => process::exit(70),
+    }
+}
+
+/// Reads a script, exiting with status 74 if it can't be read.
+fn read_source(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("Could not open file \"{}\": {}", path, err);
+            process::exit(74);
+        }
+    }
+}
+
+/// Prints every token in the file as `line:col TYPE 'lexeme'` without compiling it.
+fn dump_tokens(path: &str) {
+    let source = read_source(path);
+    for token in Scanner::new(&source).scan_tokens() {
+        println!(
+            "{}:{} {} '{}'",
+            token.line, token.column, token.ttype, token.lexeme
+        );
+    }
+}
+
+/// Compiles the file and prints its disassembly without running it.
+fn dump_bytecode(path: &str) {
+    let source = read_source(path);
+    match compile_for_dump(&source) {
+        Some(chunk) => print_disassembly(&chunk, "script"),
+        None => process::exit(65),
+    }
+}
+
+/// Compiles source for print_disassembly, without the compiler printing it as well.
+fn compile_for_dump(source: &str) -> Option<Chunk> {
+    let mut compiler = Compiler::new(source);
+    compiler.set_print_code(false);
+    compiler.compile()
+}
+
+/// Prints the disassembly of chunk followed by that of each function declared in it.
+fn print_disassembly(chunk: &Chunk, name: &str) {
+    print!("{}", chunk.disassemble(name));
+    for constant in chunk.constants() {
+        if let Value::Function(function) = constant {
+            print_disassembly(&function.chunk, &function.to_string());
+        }
+    }
 }
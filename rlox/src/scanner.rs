@@ -0,0 +1,866 @@
+use crate::token::{Token, TokenType};
+
+pub struct Scanner {
+    source: Vec<char>,
+    start: usize,
+    current: usize,
+    /// Byte offsets matching start and current. The source is indexed by char, so
+    /// these are tracked separately as characters are consumed.
+    start_byte: usize,
+    current_byte: usize,
+    line: usize,
+    /// Index of the first character on the current line.
+    line_start: usize,
+    /// Column of the token being scanned.
+    start_column: usize,
+    /// Set once the iterator has yielded Eof.
+    finished: bool,
+}
+
+impl Scanner {
+    pub fn new(source: &str) -> Self {
+        Self {
+            source: source.chars().collect(),
+            start: 0,
+            current: 0,
+            start_byte: 0,
+            current_byte: 0,
+            line: 1,
+            line_start: 0,
+            start_column: 1,
+            finished: false,
+        }
+    }
+
+    /// Scans the whole source, returning every token including errors, ending with Eof.
+    pub fn scan_tokens(&mut self) -> Vec<Token> {
+        self.collect()
+    }
+
+    /// Scans and returns the next token. Once the source is exhausted it keeps returning Eof.
+    pub fn scan_token(&mut self) -> Token {
+        if let Some(error) = self.skip_ignored() {
+            return error;
+        }
+        self.mark_start();
+
+        if self.is_at_end() {
+            return self.make_token(TokenType::Eof);
+        }
+
+        let c = self.advance();
+        if c.is_alphabetic() || c == '_' {
+            return self.identifier();
+        }
+        if c.is_ascii_digit() {
+            return self.number();
+        }
+
+        match c {
+            '(' => self.make_token(TokenType::LeftParen),
+            ')' => self.make_token(TokenType::RightParen),
+            '{' => self.make_token(TokenType::LeftBrace),
+            '}' => self.make_token(TokenType::RightBrace),
+            ';' => self.make_token(TokenType::Semicolon),
+            ',' => self.make_token(TokenType::Comma),
+            '.' => self.make_token(TokenType::Dot),
+            '-' => self.make_token(TokenType::Minus),
+            '+' => self.make_token(TokenType::Plus),
+            '/' => self.make_token(TokenType::Slash),
+            '*' => self.make_token(TokenType::Star),
+            '%' => self.make_token(TokenType::Percent),
+            '?' => self.make_token(TokenType::Question),
+            ':' => self.make_token(TokenType::Colon),
+            '!' => {
+                let ttype = if self.matches('=') {
+                    TokenType::BangEqual
+                } else {
+                    TokenType::Bang
+                };
+                self.make_token(ttype)
+            }
+            '=' => {
+                let ttype = if self.matches('=') {
+                    TokenType::EqualEqual
+                } else {
+                    TokenType::Equal
+                };
+                self.make_token(ttype)
+            }
+            '<' => {
+                let ttype = if self.matches('=') {
+                    TokenType::LessEqual
+                } else {
+                    TokenType::Less
+                };
+                self.make_token(ttype)
+            }
+            '>' => {
+                let ttype = if self.matches('=') {
+                    TokenType::GreaterEqual
+                } else {
+                    TokenType::Greater
+                };
+                self.make_token(ttype)
+            }
+            '"' => self.string(),
+            '\'' => self.char_literal(),
+            _ => self.error_token(&format!(
+                "Unexpected character '{}' at line {}.",
+                c, self.line
+            )),
+        }
+    }
+
+    /// Returns true if the current position is at the end of the source.
+    fn is_at_end(&self) -> bool {
+        self.current >= self.source.len()
+    }
+
+    /// Consumes the current character and returns it.
+    fn advance(&mut self) -> char {
+        let c = self.source[self.current];
+        self.current += 1;
+        self.current_byte += c.len_utf8();
+        c
+    }
+
+    /// Returns the character at the current position without advancing.
+    fn peek(&self) -> char {
+        self.peek_at(0)
+    }
+
+    /// Returns the character after the current one without advancing.
+    fn peek_next(&self) -> char {
+        self.peek_at(1)
+    }
+
+    /// Returns the character distance places past the current one, or '\0' past the end.
+    fn peek_at(&self, distance: usize) -> char {
+        self.source
+            .get(self.current + distance)
+            .copied()
+            .unwrap_or('\0')
+    }
+
+    /// Advances past the current character only if it is the expected one.
+    fn matches(&mut self, expected: char) -> bool {
+        if self.is_at_end() || self.source[self.current] != expected {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    /// Skips whitespace and comments, counting newlines as it goes.
+    /// Returns an error token if a block comment runs off the end of the source.
+    fn skip_ignored(&mut self) -> Option<Token> {
+        loop {
+            match self.peek() {
+                ' ' | '\t' => {
+                    self.advance();
+                }
+                '\r' | '\n' => {
+                    if self.at_line_break() {
+                        self.newline();
+                    }
+                    self.advance();
+                }
+                '/' if self.peek_next() == '/' => {
+                    // A comment goes until the end of the line.
+                    while !matches!(self.peek(), '\n' | '\r') && !self.is_at_end() {
+                        self.advance();
+                    }
+                }
+                '/' if self.peek_next() == '*' => {
+                    // An unterminated comment is reported from where it opens.
+                    self.mark_start();
+                    if !self.block_comment() {
+                        return Some(self.error_token("Unterminated block comment."));
+                    }
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// Starts the next token, or error, at the current character.
+    fn mark_start(&mut self) {
+        self.start = self.current;
+        self.start_byte = self.current_byte;
+        self.start_column = self.start - self.line_start + 1;
+    }
+
+    /// Skips a block comment, including any comments nested inside it.
+    /// Returns false if the source ends before the comment is closed.
+    fn block_comment(&mut self) -> bool {
+        // The opening "/*".
+        self.advance();
+        self.advance();
+
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return false;
+            }
+            match (self.peek(), self.peek_next()) {
+                ('/', '*') => {
+                    self.advance();
+                    depth += 1;
+                }
+                ('*', '/') => {
+                    self.advance();
+                    depth -= 1;
+                }
+                _ if self.at_line_break() => self.newline(),
+                _ => {}
+            }
+            self.advance();
+        }
+        true
+    }
+
+    /// Returns true if the current character ends a line. "\n", "\r\n" and a lone "\r" each
+    /// end one line; in "\r\n" it is the "\n" that counts.
+    fn at_line_break(&self) -> bool {
+        match self.peek() {
+            '\n' => true,
+            '\r' => self.peek_next() != '\n',
+            _ => false,
+        }
+    }
+
+    /// Records the newline at the current position. Call before advancing past it.
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current + 1;
+    }
+
+    fn make_token(&self, ttype: TokenType) -> Token {
+        Token {
+            ttype,
+            lexeme: self.source[self.start..self.current].iter().collect(),
+            line: self.line,
+            column: self.start_column,
+            len: self.current - self.start,
+            start_byte: self.start_byte,
+            end_byte: self.current_byte,
+        }
+    }
+
+    /// Builds an Error token whose lexeme is the error message.
+    fn error_token(&self, message: &str) -> Token {
+        Token {
+            ttype: TokenType::Error,
+            lexeme: message.to_string(),
+            line: self.line,
+            column: self.start_column,
+            len: self.current - self.start,
+            start_byte: self.start_byte,
+            end_byte: self.current_byte,
+        }
+    }
+
+    /// Scans a string literal. The lexeme keeps the surrounding quotes and escapes as written.
+    fn string(&mut self) -> Token {
+        let mut invalid_escape = None;
+        while self.peek() != '"' && !self.is_at_end() {
+            if self.peek() == '\\' {
+                self.advance();
+                if self.is_at_end() {
+                    break;
+                }
+                if escape(self.peek()).is_none() && invalid_escape.is_none() {
+                    invalid_escape = Some(self.peek());
+                }
+            }
+            if self.at_line_break() {
+                self.newline();
+            }
+            self.advance();
+        }
+
+        if self.is_at_end() {
+            return self.error_token("Unterminated string.");
+        }
+
+        // The closing quote.
+        self.advance();
+
+        if let Some(c) = invalid_escape {
+            return self.error_token(&format!("Invalid escape sequence '\\{}'.", c));
+        }
+        self.make_token(TokenType::String)
+    }
+
+    /// Scans a character literal, which holds exactly one character or escape sequence.
+    /// Unlike a string, it can't span lines.
+    fn char_literal(&mut self) -> Token {
+        let mut len = 0;
+        let mut invalid_escape = None;
+        while self.peek() != '\'' && !self.is_at_end() && !self.at_line_break() {
+            if self.peek() == '\\' {
+                self.advance();
+                if self.is_at_end() || self.at_line_break() {
+                    break;
+                }
+                if escape(self.peek()).is_none() && invalid_escape.is_none() {
+                    invalid_escape = Some(self.peek());
+                }
+            }
+            self.advance();
+            len += 1;
+        }
+
+        if self.peek() != '\'' {
+            return self.error_token("Unterminated character literal.");
+        }
+
+        // The closing quote.
+        self.advance();
+
+        if let Some(c) = invalid_escape {
+            return self.error_token(&format!("Invalid escape sequence '\\{}'.", c));
+        }
+        match len {
+            0 => self.error_token("Empty character literal."),
+            1 => self.make_token(TokenType::Char),
+            _ => self.error_token("Character literal too long."),
+        }
+    }
+
+    /// Scans a number literal with an optional fractional part and exponent,
+    /// or a hexadecimal or binary integer.
+    fn number(&mut self) -> Token {
+        if self.source[self.start] == '0' {
+            match self.peek() {
+                'x' | 'X' => return self.radix_number(16),
+                'b' | 'B' => return self.radix_number(2),
+                _ => {}
+            }
+        }
+
+        self.digits(10);
+
+        // Look for a fractional part.
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
+            // Consume the ".".
+            self.advance();
+
+            self.digits(10);
+        }
+
+        // Look for an exponent. Without digits after it the "e" is left for the next token.
+        if matches!(self.peek(), 'e' | 'E') {
+            let sign = usize::from(matches!(self.peek_next(), '+' | '-'));
+            if self.peek_at(1 + sign).is_ascii_digit() {
+                // Consume the "e" and any sign.
+                for _ in 0..=sign {
+                    self.advance();
+                }
+
+                self.digits(10);
+            }
+        }
+
+        self.make_token(TokenType::Number)
+    }
+
+    /// Scans the digits of an integer literal after its "0x" or "0b" prefix.
+    fn radix_number(&mut self, radix: u32) -> Token {
+        let prefix = self.advance();
+        self.digits(radix);
+
+        if self.current - self.start == 2 {
+            return self.error_token(&format!("Expect digits after '0{}'.", prefix));
+        }
+        self.make_token(TokenType::Number)
+    }
+
+    /// Consumes a run of digits, allowing single underscores between them.
+    fn digits(&mut self, radix: u32) {
+        loop {
+            while self.peek().is_digit(radix) {
+                self.advance();
+            }
+            if self.peek() == '_' && self.peek_next().is_digit(radix) {
+                self.advance();
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// Scans an identifier or keyword. Identifiers start with any Unicode letter or '_'
+    /// and continue with letters, digits or '_'.
+    fn identifier(&mut self) -> Token {
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
+            self.advance();
+        }
+        self.make_token(self.identifier_type())
+    }
+
+    /// Tells keywords apart from identifiers.
+    fn identifier_type(&self) -> TokenType {
+        let lexeme: String = self.source[self.start..self.current].iter().collect();
+        keyword(&lexeme).unwrap_or(TokenType::Identifier)
+    }
+}
+
+/// Yields each token up to and including Eof, then None.
+impl Iterator for Scanner {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        if self.finished {
+            return None;
+        }
+        let token = self.scan_token();
+        self.finished = token.ttype == TokenType::Eof;
+        Some(token)
+    }
+}
+
+/// Returns the token type of a reserved word, or None for any other identifier.
+fn keyword(lexeme: &str) -> Option<TokenType> {
+    let ttype = match lexeme {
+        "and" => TokenType::And,
+        "break" => TokenType::Break,
+        "class" => TokenType::Class,
+        "continue" => TokenType::Continue,
+        "else" => TokenType::Else,
+        "false" => TokenType::False,
+        "for" => TokenType::For,
+        "fun" => TokenType::Fun,
+        "if" => TokenType::If,
+        "nil" => TokenType::Nil,
+        "or" => TokenType::Or,
+        "print" => TokenType::Print,
+        "return" => TokenType::Return,
+        "super" => TokenType::Super,
+        "this" => TokenType::This,
+        "true" => TokenType::True,
+        "var" => TokenType::Var,
+        "while" => TokenType::While,
+        _ => return None,
+    };
+    Some(ttype)
+}
+
+/// Returns the character an escape sequence stands for, given the character after the backslash.
+fn escape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '\\' => Some('\\'),
+        '"' => Some('"'),
+        '\'' => Some('\''),
+        '0' => Some('\0'),
+        _ => None,
+    }
+}
+
+/// Decodes the escape sequences in the body of a string literal the scanner has accepted.
+pub fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(escaped) => out.push(escape(escaped).unwrap_or(escaped)),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan_all(source: &str) -> Vec<Token> {
+        Scanner::new(source).scan_tokens()
+    }
+
+    #[test]
+    fn scans_a_declaration() {
+        let types: Vec<_> = scan_all("var x = 1;").iter().map(|t| t.ttype).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Var,
+                TokenType::Identifier,
+                TokenType::Equal,
+                TokenType::Number,
+                TokenType::Semicolon,
+                TokenType::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn scans_every_keyword() {
+        let keywords = [
+            ("and", TokenType::And),
+            ("break", TokenType::Break),
+            ("class", TokenType::Class),
+            ("continue", TokenType::Continue),
+            ("else", TokenType::Else),
+            ("false", TokenType::False),
+            ("for", TokenType::For),
+            ("fun", TokenType::Fun),
+            ("if", TokenType::If),
+            ("nil", TokenType::Nil),
+            ("or", TokenType::Or),
+            ("print", TokenType::Print),
+            ("return", TokenType::Return),
+            ("super", TokenType::Super),
+            ("this", TokenType::This),
+            ("true", TokenType::True),
+            ("var", TokenType::Var),
+            ("while", TokenType::While),
+        ];
+        for (text, ttype) in keywords {
+            assert_eq!(scan_all(text)[0].ttype, ttype, "{}", text);
+        }
+
+        for text in ["f", "t", "an", "classy", "fo", "Nil", "_if"] {
+            assert_eq!(scan_all(text)[0].ttype, TokenType::Identifier, "{}", text);
+        }
+    }
+
+    #[test]
+    fn scans_unicode_identifiers() {
+        for text in ["café", "_x", "λ", "naïve_2", "ifé", "ñil"] {
+            let tokens = scan_all(text);
+            assert_eq!(tokens[0].ttype, TokenType::Identifier, "{}", text);
+            assert_eq!(tokens[0].lexeme, text);
+            assert_eq!(tokens[0].len, text.chars().count());
+            assert_eq!(tokens[1].ttype, TokenType::Eof);
+        }
+    }
+
+    #[test]
+    fn iterates_over_tokens() {
+        let mut scanner = Scanner::new("print 1;");
+        let lexemes: Vec<_> = scanner.by_ref().map(|t| t.lexeme).collect();
+        assert_eq!(lexemes, vec!["print", "1", ";", ""]);
+        assert_eq!(scanner.next(), None);
+        assert_eq!(scanner.next(), None);
+
+        let numbers = Scanner::new("1 + 2 * 3")
+            .filter(|t| t.ttype == TokenType::Number)
+            .count();
+        assert_eq!(numbers, 3);
+    }
+
+    #[test]
+    fn scans_percent() {
+        let types: Vec<_> = scan_all("7 % 3").iter().map(|t| t.ttype).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Number,
+                TokenType::Percent,
+                TokenType::Number,
+                TokenType::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn scans_question_and_colon() {
+        let types: Vec<_> = scan_all("a ?b: c").iter().map(|t| t.ttype).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Identifier,
+                TokenType::Question,
+                TokenType::Identifier,
+                TokenType::Colon,
+                TokenType::Identifier,
+                TokenType::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_scanning_past_errors() {
+        let types: Vec<_> = scan_all("1 @ 2").iter().map(|t| t.ttype).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Number,
+                TokenType::Error,
+                TokenType::Number,
+                TokenType::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn unexpected_character_error_names_it() {
+        let tokens = scan_all("@");
+        assert_eq!(tokens[0].ttype, TokenType::Error);
+        assert_eq!(tokens[0].lexeme, "Unexpected character '@' at line 1.");
+
+        let tokens = scan_all("1;\n\n  #");
+        assert_eq!(tokens[2].lexeme, "Unexpected character '#' at line 3.");
+        assert_eq!((tokens[2].column, tokens[2].len), (3, 1));
+    }
+
+    #[test]
+    fn scans_a_fractional_number() {
+        let tokens = scan_all("3.14");
+        assert_eq!(tokens[0].ttype, TokenType::Number);
+        assert_eq!(tokens[0].lexeme, "3.14");
+        assert_eq!(tokens[1].ttype, TokenType::Eof);
+    }
+
+    #[test]
+    fn trailing_dot_is_not_part_of_the_number() {
+        let tokens = scan_all("1.");
+        assert_eq!(tokens[0].ttype, TokenType::Number);
+        assert_eq!(tokens[0].lexeme, "1");
+        assert_eq!(tokens[1].ttype, TokenType::Dot);
+        assert_eq!(tokens[2].ttype, TokenType::Eof);
+    }
+
+    #[test]
+    fn scans_an_exponent() {
+        for source in ["1e10", "2.5e-3", "6.022E23", "1e+2"] {
+            let tokens = scan_all(source);
+            assert_eq!(tokens[0].ttype, TokenType::Number, "{}", source);
+            assert_eq!(tokens[0].lexeme, source);
+            assert_eq!(tokens[1].ttype, TokenType::Eof);
+        }
+    }
+
+    #[test]
+    fn exponent_without_digits_ends_the_number() {
+        let tokens = scan_all("1e");
+        assert_eq!(tokens[0].ttype, TokenType::Number);
+        assert_eq!(tokens[0].lexeme, "1");
+        assert_eq!(tokens[1].ttype, TokenType::Identifier);
+        assert_eq!(tokens[1].lexeme, "e");
+        assert_eq!(tokens[2].ttype, TokenType::Eof);
+
+        let lexemes: Vec<_> = scan_all("1e-").into_iter().map(|t| t.lexeme).collect();
+        assert_eq!(lexemes, vec!["1", "e", "-", ""]);
+    }
+
+    #[test]
+    fn scans_hexadecimal_and_binary_integers() {
+        for source in ["0xFF", "0Xff", "0b1010", "0B1"] {
+            let tokens = scan_all(source);
+            assert_eq!(tokens[0].ttype, TokenType::Number, "{}", source);
+            assert_eq!(tokens[0].lexeme, source);
+            assert_eq!(tokens[1].ttype, TokenType::Eof);
+        }
+    }
+
+    #[test]
+    fn radix_prefix_without_digits_is_an_error() {
+        let tokens = scan_all("0x;");
+        assert_eq!(tokens[0].ttype, TokenType::Error);
+        assert_eq!(tokens[0].lexeme, "Expect digits after '0x'.");
+        assert_eq!(tokens[1].ttype, TokenType::Semicolon);
+
+        let tokens = scan_all("0b2");
+        assert_eq!(tokens[0].lexeme, "Expect digits after '0b'.");
+        assert_eq!(tokens[1].ttype, TokenType::Number);
+    }
+
+    #[test]
+    fn scans_digit_separators() {
+        for source in ["1_000", "3.14_15", "1_0e1_0", "0xFF_FF"] {
+            let tokens = scan_all(source);
+            assert_eq!(tokens[0].ttype, TokenType::Number, "{}", source);
+            assert_eq!(tokens[0].lexeme, source);
+            assert_eq!(tokens[1].ttype, TokenType::Eof);
+        }
+    }
+
+    #[test]
+    fn separators_must_sit_between_digits() {
+        let tokens = scan_all("1_");
+        assert_eq!(tokens[0].ttype, TokenType::Number);
+        assert_eq!(tokens[0].lexeme, "1");
+        assert_eq!(tokens[1].ttype, TokenType::Identifier);
+        assert_eq!(tokens[1].lexeme, "_");
+
+        let lexemes: Vec<_> = scan_all("1__0").into_iter().map(|t| t.lexeme).collect();
+        assert_eq!(lexemes, vec!["1", "__0", ""]);
+        let lexemes: Vec<_> = scan_all("1._5").into_iter().map(|t| t.lexeme).collect();
+        assert_eq!(lexemes, vec!["1", ".", "_5", ""]);
+    }
+
+    #[test]
+    fn slash_before_a_comment_at_the_end_of_the_source() {
+        let tokens = scan_all("1 / 2 //");
+        let types: Vec<_> = tokens.iter().map(|token| token.ttype).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Number,
+                TokenType::Slash,
+                TokenType::Number,
+                TokenType::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_each_supported_escape() {
+        let cases = [
+            (r#""a\nb""#, "a\nb"),
+            (r#""a\tb""#, "a\tb"),
+            (r#""a\rb""#, "a\rb"),
+            (r#""a\\b""#, "a\\b"),
+            (r#""a\"b""#, "a\"b"),
+            (r#""a\0b""#, "a\0b"),
+        ];
+        for (source, expected) in cases {
+            let tokens = scan_all(source);
+            assert_eq!(tokens[0].ttype, TokenType::String, "{}", source);
+            assert_eq!(tokens[0].lexeme, source);
+            assert_eq!(tokens[1].ttype, TokenType::Eof);
+
+            let body = &source[1..source.len() - 1];
+            assert_eq!(unescape(body), expected);
+        }
+    }
+
+    #[test]
+    fn unknown_escape_is_an_error() {
+        let tokens = scan_all(r#""a\qb" 1"#);
+        assert_eq!(tokens[0].ttype, TokenType::Error);
+        assert_eq!(tokens[0].lexeme, "Invalid escape sequence '\\q'.");
+        // Scanning picks up after the bad string.
+        assert_eq!(tokens[1].ttype, TokenType::Number);
+    }
+
+    #[test]
+    fn escaped_quote_at_the_end_leaves_the_string_unterminated() {
+        let tokens = scan_all(r#""abc\""#);
+        assert_eq!(tokens[0].ttype, TokenType::Error);
+        assert_eq!(tokens[0].lexeme, "Unterminated string.");
+    }
+
+    #[test]
+    fn scans_char_literals() {
+        let tokens = scan_all(r"'a' '\n' '\'' 'é'");
+        let lexemes: Vec<_> = tokens[..4].iter().map(|t| t.lexeme.as_str()).collect();
+        assert_eq!(lexemes, [r"'a'", r"'\n'", r"'\''", "'é'"]);
+        assert!(tokens[..4].iter().all(|t| t.ttype == TokenType::Char));
+        assert_eq!(tokens[4].ttype, TokenType::Eof);
+    }
+
+    #[test]
+    fn malformed_char_literals_are_errors() {
+        for (source, message) in [
+            ("''", "Empty character literal."),
+            ("'ab'", "Character literal too long."),
+            (r"'\q'", r"Invalid escape sequence '\q'."),
+            ("'a", "Unterminated character literal."),
+            ("'a\n'", "Unterminated character literal."),
+        ] {
+            let tokens = scan_all(source);
+            assert_eq!(tokens[0].ttype, TokenType::Error, "{}", source);
+            assert_eq!(tokens[0].lexeme, message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn skips_a_block_comment() {
+        let tokens = scan_all("1 /* two\nlines */ 2");
+        assert_eq!(tokens[0].lexeme, "1");
+        assert_eq!(tokens[1].lexeme, "2");
+        assert_eq!(tokens[1].line, 2);
+        assert_eq!(tokens[2].ttype, TokenType::Eof);
+    }
+
+    #[test]
+    fn skips_nested_block_comments() {
+        let tokens = scan_all("/* outer /* inner */ still comment */ 1");
+        assert_eq!(tokens[0].ttype, TokenType::Number);
+        assert_eq!(tokens[1].ttype, TokenType::Eof);
+    }
+
+    #[test]
+    fn unterminated_block_comment_is_an_error() {
+        let tokens = scan_all("1 /* outer /* inner */");
+        assert_eq!(tokens[0].ttype, TokenType::Number);
+        assert_eq!(tokens[1].ttype, TokenType::Error);
+        assert_eq!(tokens[1].lexeme, "Unterminated block comment.");
+        assert_eq!(tokens[2].ttype, TokenType::Eof);
+    }
+
+    #[test]
+    fn unterminated_block_comment_spans_from_its_opening() {
+        let tokens = scan_all("12 /* x");
+        let error = &tokens[1];
+        assert_eq!(error.ttype, TokenType::Error);
+        assert_eq!((error.column, error.len), (4, 4));
+        assert_eq!((error.start_byte, error.end_byte), (3, 7));
+    }
+
+    #[test]
+    fn tracks_columns_and_lengths() {
+        let tokens = scan_all("1 + 2");
+        assert_eq!(tokens[1].ttype, TokenType::Plus);
+        assert_eq!((tokens[1].column, tokens[1].len), (3, 1));
+        assert_eq!((tokens[2].column, tokens[2].len), (5, 1));
+    }
+
+    #[test]
+    fn spans_are_byte_offsets() {
+        let source = "var s = \"héllo\"; s";
+        let tokens = scan_all(source);
+
+        let string = &tokens[3];
+        assert_eq!(string.ttype, TokenType::String);
+        assert_eq!((string.start_byte, string.end_byte), (8, 16));
+        assert_eq!(&source[string.start_byte..string.end_byte], "\"héllo\"");
+        // Columns and lengths still count characters.
+        assert_eq!((string.column, string.len), (9, 7));
+
+        for token in &tokens {
+            let expected = if token.ttype == TokenType::Eof {
+                ""
+            } else {
+                token.lexeme.as_str()
+            };
+            assert_eq!(&source[token.start_byte..token.end_byte], expected);
+        }
+    }
+
+    #[test]
+    fn counts_every_kind_of_line_ending() {
+        let tokens = scan_all("a\r\nb\rc\nd");
+        let positions: Vec<_> = tokens.iter().map(|t| (t.line, t.column)).collect();
+        assert_eq!(positions, vec![(1, 1), (2, 1), (3, 1), (4, 1), (4, 2)]);
+
+        let tokens = scan_all("// one\r/* two\r\nthree\r */ \"four\rfive\" x");
+        assert_eq!(tokens[0].ttype, TokenType::String);
+        assert_eq!(tokens[0].line, 5);
+        assert_eq!(tokens[1].lexeme, "x");
+        assert_eq!((tokens[1].line, tokens[1].column), (5, 7));
+    }
+
+    #[test]
+    fn columns_restart_on_each_line() {
+        let tokens = scan_all("var x;\n  print x;");
+        let print = &tokens[3];
+        assert_eq!(print.ttype, TokenType::Print);
+        assert_eq!((print.line, print.column, print.len), (2, 3, 5));
+
+        let tokens = scan_all("\"a\nb\" c");
+        assert_eq!((tokens[0].column, tokens[0].len), (1, 5));
+        assert_eq!((tokens[1].line, tokens[1].column), (2, 4));
+    }
+}
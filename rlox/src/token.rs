@@ -0,0 +1,121 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenType {
+    // Single-character tokens.
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Dot,
+    Minus,
+    Plus,
+    Semicolon,
+    Slash,
+    Star,
+    Percent,
+    Question,
+    Colon,
+    // One or two character tokens.
+    Bang,
+    BangEqual,
+    Equal,
+    EqualEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    // Literals.
+    Identifier,
+    String,
+    Char,
+    Number,
+    // Keywords.
+    And,
+    Break,
+    Class,
+    Continue,
+    Else,
+    False,
+    For,
+    Fun,
+    If,
+    Nil,
+    Or,
+    Print,
+    Return,
+    Super,
+    This,
+    True,
+    Var,
+    While,
+
+    Error,
+    Eof,
+}
+
+impl fmt::Display for TokenType {
+    /// Prints the token type in SCREAMING_SNAKE_CASE, e.g. `LEFT_PAREN`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = format!("{:?}", self);
+        let mut out = String::with_capacity(name.len() + 4);
+        for (i, c) in name.chars().enumerate() {
+            if i > 0 && c.is_uppercase() {
+                out.push('_');
+            }
+            out.push(c.to_ascii_uppercase());
+        }
+        f.write_str(&out)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub ttype: TokenType,
+    pub lexeme: String,
+    pub line: usize,
+    /// 1-based column of the first character of the token.
+    pub column: usize,
+    /// Length of the token in characters.
+    pub len: usize,
+    /// Byte offset of the token's first byte in the source.
+    pub start_byte: usize,
+    /// Byte offset just past the token's last byte in the source.
+    pub end_byte: usize,
+}
+
+impl Default for Token {
+    /// An empty Eof token, used as a placeholder before scanning starts.
+    fn default() -> Self {
+        Self {
+            ttype: TokenType::Eof,
+            lexeme: String::new(),
+            line: 0,
+            column: 0,
+            len: 0,
+            start_byte: 0,
+            end_byte: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displays_token_types_in_screaming_snake_case() {
+        assert_eq!(TokenType::LeftParen.to_string(), "LEFT_PAREN");
+        assert_eq!(TokenType::BangEqual.to_string(), "BANG_EQUAL");
+        assert_eq!(TokenType::Eof.to_string(), "EOF");
+    }
+
+    #[test]
+    fn default_token_is_eof() {
+        let token = Token::default();
+        assert_eq!(token.ttype, TokenType::Eof);
+        assert!(token.lexeme.is_empty());
+        assert_eq!(token.line, 0);
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Bool(bool),
+    Nil,
+    Str(Rc<str>),
+    Function(Rc<Function>),
+    NativeFn(Rc<Native>),
+}
+
+/// Function is a compiled Lox function. Declarations store it in their chunk as a constant.
+#[derive(Debug, Default)]
+pub struct Function {
+    pub arity: usize,
+    pub chunk: Chunk,
+    /// None for the top-level script.
+    pub name: Option<Rc<str>>,
+}
+
+impl PartialEq for Function {
+    /// Functions are only equal to themselves.
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// Native is a function implemented in Rust that scripts can call.
+#[derive(Debug)]
+pub struct Native {
+    pub arity: usize,
+    /// Called with exactly arity arguments.
+    pub function: fn(&[Value]) -> Value,
+}
+
+impl PartialEq for Native {
+    /// Natives are only equal to themselves.
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(number) => write!(f, "{}", number),
+            Value::Bool(boolean) => write!(f, "{}", boolean),
+            Value::Nil => write!(f, "nil"),
+            Value::Str(string) => write!(f, "{}", string),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::NativeFn(_) => write!(f, "<native fn>"),
+        }
+    }
+}
+
+/// Lox treats nil and false as falsey and every other value as truthy.
+pub fn is_falsey(value: &Value) -> bool {
+    matches!(value, Value::Nil | Value::Bool(false))
+}
+
+/// ValueArray is the constant pool of a chunk.
+#[derive(Debug, Default, Clone)]
+pub struct ValueArray {
+    values: Vec<Value>,
+}
+
+impl ValueArray {
+    pub fn new() -> Self {
+        Self { values: Vec::new() }
+    }
+
+    /// Appends a value and returns its index.
+    pub fn write(&mut self, value: Value) -> usize {
+        self.values.push(value);
+        self.values.len() - 1
+    }
+
+    pub fn read(&self, index: usize) -> Value {
+        self.values[index].clone()
+    }
+
+    /// Keeps the first len values and drops the rest.
+    pub fn truncate(&mut self, len: usize) {
+        self.values.truncate(len);
+    }
+
+    pub fn as_slice(&self) -> &[Value] {
+        &self.values
+    }
+
+    /// Returns the index of a value identical to value. Numbers are compared bit for bit,
+    /// so 0 and -0 stay apart and a NaN matches itself.
+    pub fn find(&self, value: &Value) -> Option<usize> {
+        self.values
+            .iter()
+            .position(|existing| match (existing, value) {
+                (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+                _ => existing == value,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displays_each_variant() {
+        assert_eq!(Value::Number(42.0).to_string(), "42");
+        assert_eq!(Value::Number(2.5).to_string(), "2.5");
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(Value::Nil.to_string(), "nil");
+        assert_eq!(Value::Str("foo".into()).to_string(), "foo");
+
+        let mut function = Function::default();
+        assert_eq!(Value::Function(Rc::new(function)).to_string(), "<script>");
+        function = Function {
+            name: Some("add".into()),
+            ..Function::default()
+        };
+        assert_eq!(Value::Function(Rc::new(function)).to_string(), "<fn add>");
+    }
+
+    #[test]
+    fn finds_identical_values() {
+        let mut array = ValueArray::new();
+        array.write(Value::Number(0.0));
+        array.write(Value::Number(f64::NAN));
+        array.write(Value::Str("lox".into()));
+
+        assert_eq!(array.find(&Value::Number(0.0)), Some(0));
+        assert_eq!(array.find(&Value::Number(-0.0)), None);
+        assert_eq!(array.find(&Value::Number(f64::NAN)), Some(1));
+        assert_eq!(array.find(&Value::Str("lox".into())), Some(2));
+        assert_eq!(array.find(&Value::Nil), None);
+    }
+
+    #[test]
+    fn only_nil_and_false_are_falsey() {
+        assert!(is_falsey(&Value::Nil));
+        assert!(is_falsey(&Value::Bool(false)));
+        assert!(!is_falsey(&Value::Bool(true)));
+        assert!(!is_falsey(&Value::Number(0.0)));
+    }
+}
@@ -0,0 +1,1330 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chunk::{Chunk, OpCode};
+use crate::compiler::Compiler;
+use crate::value::{is_falsey, Function, Native, Value};
+
+/// The deepest calls can nest before the VM reports a stack overflow.
+pub const FRAMES_MAX: usize = 64;
+/// The most values the stack can hold before the VM reports a stack overflow. Each frame
+/// can address 256 slots with its one-byte operands, so every frame gets room for that many.
+pub const STACK_MAX: usize = FRAMES_MAX * 256;
+
+/// InterpretError says which stage failed. The details have already been reported on stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpretError {
+    CompileError,
+    RuntimeError,
+}
+
+/// A callback run before each instruction with the ip, the chunk and the stack.
+pub type InstructionHook = Box<dyn FnMut(usize, &Chunk, &[Value])>;
+
+/// StepResult says what happened when the VM executed one instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// There are more instructions to run.
+    Continue,
+    /// The script returned.
+    Halted,
+    /// The instruction failed. The error has been reported and the stack reset.
+    Error(InterpretError),
+}
+
+/// CallFrame is a function call in progress.
+struct CallFrame {
+    function: Rc<Function>,
+    /// Where to resume once the function this frame called returns. The running frame's
+    /// ip is kept in VM::ip instead.
+    ip: usize,
+    /// The index of the frame's slot 0 on the stack, which holds the function itself.
+    slots: usize,
+}
+
+pub struct VM {
+    /// The ip of the innermost frame.
+    ip: usize,
+    frames: Vec<CallFrame>,
+    stack: Vec<Value>,
+    globals: HashMap<Rc<str>, Value>,
+    /// Where program output goes. Errors still go to stderr.
+    out: Box<dyn Write>,
+    /// Called before each instruction executes. The debug_trace_exec feature installs trace.
+    on_instruction: Option<InstructionHook>,
+    /// Makes dividing by zero a runtime error. Otherwise / and % follow IEEE 754, so
+    /// 1 / 0 is inf and 1 % 0 is NaN.
+    strict_division: bool,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> Self {
+        Self::with_writer(Box::new(io::stdout()))
+    }
+
+    /// Creates a VM that writes program output to out instead of stdout.
+    pub fn with_writer(out: Box<dyn Write>) -> Self {
+        let mut vm = Self {
+            ip: 0,
+            frames: Vec::new(),
+            stack: Vec::new(),
+            globals: HashMap::new(),
+            out,
+            on_instruction: cfg!(feature = "debug_trace_exec")
+                .then(|| Box::new(trace) as InstructionHook),
+            strict_division: false,
+        };
+        vm.define_natives();
+        vm
+    }
+
+    fn define_natives(&mut self) {
+        self.define_native("clock", 0, clock);
+    }
+
+    /// Forgets every global, leaving only the built-in natives defined.
+    pub fn reset_globals(&mut self) {
+        self.globals.clear();
+        self.define_natives();
+    }
+
+    /// Makes a Rust function callable from scripts as a global named name.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: fn(&[Value]) -> Value) {
+        let native = Native { arity, function };
+        self.globals
+            .insert(name.into(), Value::NativeFn(Rc::new(native)));
+    }
+
+    /// Installs a hook to run before each instruction, replacing any earlier one.
+    pub fn set_on_instruction(&mut self, hook: impl FnMut(usize, &Chunk, &[Value]) + 'static) {
+        self.on_instruction = Some(Box::new(hook));
+    }
+
+    /// Turns strict division on or off. See strict_division.
+    pub fn set_strict_division(&mut self, strict: bool) {
+        self.strict_division = strict;
+    }
+
+    /// Compiles and runs source.
+    pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
+        let mut compiler = Compiler::new(source);
+        let Some(chunk) = compiler.compile() else {
+            return Err(InterpretError::CompileError);
+        };
+
+        self.load(chunk);
+        self.run()
+    }
+
+    /// Sets chunk up as the top-level script on an empty stack, ready to step through
+    /// from its start.
+    pub fn load(&mut self, chunk: Chunk) {
+        let script = Function {
+            arity: 0,
+            chunk,
+            name: None,
+        };
+        self.stack.clear();
+        self.frames.clear();
+        self.frames.push(CallFrame {
+            function: Rc::new(script),
+            ip: 0,
+            slots: 0,
+        });
+        self.ip = 0;
+    }
+
+    fn run(&mut self) -> Result<(), InterpretError> {
+        loop {
+            match self.step() {
+                StepResult::Continue => {}
+                StepResult::Halted => return Ok(()),
+                StepResult::Error(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Executes the instruction at ip, leaving the VM ready for the next one.
+    pub fn step(&mut self) -> StepResult {
+        self.execute().unwrap_or_else(StepResult::Error)
+    }
+
+    /// Does the work of step, returning errors as Err so the handlers can use ?.
+    fn execute(&mut self) -> Result<StepResult, InterpretError> {
+        let Some(frame) = self.frames.last() else {
+            return Ok(StepResult::Halted);
+        };
+        let slots = frame.slots;
+        let function = Rc::clone(&frame.function);
+        let chunk = &function.chunk;
+
+        if let Some(hook) = self.on_instruction.as_mut() {
+            hook(self.ip, chunk, &self.stack);
+        }
+
+        let instruction = match OpCode::try_from(self.read_byte(chunk)?) {
+            Ok(instruction) => instruction,
+            Err(err) => return Err(self.runtime_error(&err.to_string())),
+        };
+        match instruction {
+            OpCode::Constant => {
+                let constant = self.read_constant(chunk)?;
+                self.push(constant)?;
+            }
+            OpCode::Nil => self.push(Value::Nil)?,
+            OpCode::True => self.push(Value::Bool(true))?,
+            OpCode::False => self.push(Value::Bool(false))?,
+            OpCode::Pop => {
+                self.pop()?;
+            }
+            OpCode::Dup => {
+                let top = self.peek(0)?;
+                self.push(top)?;
+            }
+            OpCode::PopN => {
+                let count = usize::from(self.read_byte(chunk)?);
+                let Some(depth) = self.stack.len().checked_sub(count) else {
+                    return Err(self.runtime_error("Stack underflow."));
+                };
+                self.stack.truncate(depth);
+            }
+            OpCode::GetLocal => {
+                let slot = self.read_local(chunk, slots)?;
+                let value = self.stack[slot].clone();
+                self.push(value)?;
+            }
+            OpCode::SetLocal => {
+                let slot = self.read_local(chunk, slots)?;
+                // Like SetGlobal, the assigned value stays on the stack.
+                self.stack[slot] = self.peek(0)?;
+            }
+            OpCode::GetGlobal => {
+                let name = self.read_string(chunk)?;
+                let Some(value) = self.globals.get(&name).cloned() else {
+                    let message = format!("Undefined variable '{}'.", name);
+                    return Err(self.runtime_error(&message));
+                };
+                self.push(value)?;
+            }
+            OpCode::DefineGlobal => {
+                let name = self.read_string(chunk)?;
+                let value = self.pop()?;
+                self.globals.insert(name, value);
+            }
+            OpCode::SetGlobal => {
+                let name = self.read_string(chunk)?;
+                // Assignment doesn't pop: the value is the result of the expression.
+                let value = self.peek(0)?;
+                match self.globals.get_mut(&name) {
+                    Some(slot) => *slot = value,
+                    None => {
+                        let message = format!("Undefined variable '{}'.", name);
+                        return Err(self.runtime_error(&message));
+                    }
+                }
+            }
+            OpCode::Equal => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.stack.push(Value::Bool(a == b));
+            }
+            OpCode::Greater => {
+                self.binary_op(|a, b| Value::Bool(a > b))?;
+            }
+            OpCode::Less => {
+                self.binary_op(|a, b| Value::Bool(a < b))?;
+            }
+            OpCode::Add => {
+                let sum = match (self.peek(1)?, self.peek(0)?) {
+                    (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+                    (Value::Str(a), Value::Str(b)) => Value::Str(format!("{}{}", a, b).into()),
+                    (a, b) => {
+                        let message = format!(
+                            "Operands must be two numbers or two strings, got {} and {}.",
+                            a, b
+                        );
+                        return Err(self.runtime_error(&message));
+                    }
+                };
+                self.stack.pop();
+                self.stack.pop();
+                self.stack.push(sum);
+            }
+            OpCode::Subtract => {
+                self.binary_op(|a, b| Value::Number(a - b))?;
+            }
+            OpCode::Multiply => {
+                self.binary_op(|a, b| Value::Number(a * b))?;
+            }
+            OpCode::Divide => {
+                self.check_divisor()?;
+                self.binary_op(|a, b| Value::Number(a / b))?;
+            }
+            OpCode::Modulo => {
+                self.check_divisor()?;
+                // Rust's % truncates, like C's fmod: the result takes the sign of a.
+                self.binary_op(|a, b| Value::Number(a % b))?;
+            }
+            OpCode::Not => {
+                let value = self.pop()?;
+                self.stack.push(Value::Bool(is_falsey(&value)));
+            }
+            OpCode::Negate => {
+                let Value::Number(value) = self.peek(0)? else {
+                    return Err(self.runtime_error("Operand must be a number."));
+                };
+                self.stack.pop();
+                self.stack.push(Value::Number(-value));
+            }
+            OpCode::Call => {
+                let arg_count = usize::from(self.read_byte(chunk)?);
+                self.call_value(arg_count)?;
+            }
+            OpCode::Print => {
+                let value = self.pop()?;
+                writeln!(self.out, "{}", value).expect("failed writing program output");
+            }
+            OpCode::Jump => {
+                let offset = self.read_short(chunk)?;
+                self.ip += usize::from(offset);
+            }
+            OpCode::JumpIfFalse => {
+                let offset = self.read_short(chunk)?;
+                if is_falsey(&self.peek(0)?) {
+                    self.ip += usize::from(offset);
+                }
+            }
+            OpCode::Loop => {
+                let offset = self.read_short(chunk)?;
+                let Some(target) = self.ip.checked_sub(usize::from(offset)) else {
+                    return Err(self.runtime_error("Loop jumps before the start of the code."));
+                };
+                self.ip = target;
+            }
+            OpCode::Return => {
+                // Returning from the script halts, leaving the stack for the caller to inspect.
+                if self.frames.len() == 1 {
+                    self.frames.pop();
+                    return Ok(StepResult::Halted);
+                }
+                let result = self.pop()?;
+                let frame = self.frames.pop().unwrap();
+                self.ip = self.frames.last().unwrap().ip;
+                self.stack.truncate(frame.slots);
+                self.stack.push(result);
+            }
+        }
+        Ok(StepResult::Continue)
+    }
+
+    /// Pushes value, reporting a stack overflow instead of growing past STACK_MAX.
+    /// Instructions that pop before they push can't overflow and push directly.
+    fn push(&mut self, value: Value) -> Result<(), InterpretError> {
+        if self.stack_depth() >= STACK_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Returns a copy of the value distance slots down from the top of the stack without
+    /// popping it. Reaching past the bottom, which only bad bytecode does, is a runtime
+    /// error rather than a panic.
+    fn peek(&mut self, distance: usize) -> Result<Value, InterpretError> {
+        match self.stack.len().checked_sub(distance + 1) {
+            Some(index) => Ok(self.stack[index].clone()),
+            None => Err(self.runtime_error("Stack underflow.")),
+        }
+    }
+
+    /// Pops the top of the stack, reporting a runtime error if it is empty.
+    fn pop(&mut self) -> Result<Value, InterpretError> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.runtime_error("Stack underflow.")),
+        }
+    }
+
+    /// Returns the stack, bottom first.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Returns how many values are on the stack.
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Reads the next byte, reporting a runtime error if the code ends first.
+    fn read_byte(&mut self, chunk: &Chunk) -> Result<u8, InterpretError> {
+        let Some(byte) = chunk.read(self.ip) else {
+            return Err(self.runtime_error("Unexpected end of bytecode."));
+        };
+        self.ip += 1;
+        Ok(byte)
+    }
+
+    /// Reads a big-endian 16-bit operand.
+    fn read_short(&mut self, chunk: &Chunk) -> Result<u16, InterpretError> {
+        Ok(u16::from_be_bytes([
+            self.read_byte(chunk)?,
+            self.read_byte(chunk)?,
+        ]))
+    }
+
+    fn read_constant(&mut self, chunk: &Chunk) -> Result<Value, InterpretError> {
+        let index = self.read_byte(chunk)? as usize;
+        Ok(chunk.get_constant(index))
+    }
+
+    /// Reads a constant operand that names a variable. The compiler always makes it a
+    /// string, but a deserialized chunk might not.
+    fn read_string(&mut self, chunk: &Chunk) -> Result<Rc<str>, InterpretError> {
+        match self.read_constant(chunk)? {
+            Value::Str(string) => Ok(string),
+            value => {
+                let message = format!("Variable name must be a string, got {}.", value);
+                Err(self.runtime_error(&message))
+            }
+        }
+    }
+
+    /// Reads a local's slot operand and returns its index in the stack, reporting a
+    /// runtime error if bad bytecode names a slot above the top.
+    fn read_local(&mut self, chunk: &Chunk, slots: usize) -> Result<usize, InterpretError> {
+        let slot = slots + usize::from(self.read_byte(chunk)?);
+        if slot >= self.stack.len() {
+            return Err(self.runtime_error("Local slot is past the top of the stack."));
+        }
+        Ok(slot)
+    }
+
+    /// Pops two number operands and pushes the result of applying op to them.
+    fn binary_op(&mut self, op: fn(f64, f64) -> Value) -> Result<(), InterpretError> {
+        let (a, b) = (self.peek(1)?, self.peek(0)?);
+        let (Value::Number(a), Value::Number(b)) = (&a, &b) else {
+            let message = format!("Operands must be numbers, got {} and {}.", a, b);
+            return Err(self.runtime_error(&message));
+        };
+        let (a, b) = (*a, *b);
+        self.stack.pop();
+        self.stack.pop();
+        self.stack.push(op(a, b));
+        Ok(())
+    }
+
+    /// Calls the value below the arg_count arguments on top of the stack, replacing the
+    /// callee and arguments with the result.
+    fn call_value(&mut self, arg_count: usize) -> Result<(), InterpretError> {
+        let native = match self.peek(arg_count)? {
+            Value::Function(function) => return self.call(function, arg_count),
+            Value::NativeFn(native) => native,
+            _ => return Err(self.runtime_error("Can only call functions and classes.")),
+        };
+        if arg_count != native.arity {
+            let message = format!("Expected {} arguments but got {}.", native.arity, arg_count);
+            return Err(self.runtime_error(&message));
+        }
+
+        let args_start = self.stack.len() - arg_count;
+        let result = (native.function)(&self.stack[args_start..]);
+        self.stack.truncate(args_start - 1);
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// Pushes a frame for function, whose arguments are already on top of the stack.
+    fn call(&mut self, function: Rc<Function>, arg_count: usize) -> Result<(), InterpretError> {
+        if arg_count != function.arity {
+            let message = format!(
+                "Expected {} arguments but got {}.",
+                function.arity, arg_count
+            );
+            return Err(self.runtime_error(&message));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        if let Some(caller) = self.frames.last_mut() {
+            caller.ip = self.ip;
+        }
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        self.ip = 0;
+        Ok(())
+    }
+
+    /// In strict mode, reports a runtime error if a number is about to be divided by zero.
+    fn check_divisor(&mut self) -> Result<(), InterpretError> {
+        if !self.strict_division {
+            return Ok(());
+        }
+        match (self.peek(1)?, self.peek(0)?) {
+            (Value::Number(_), Value::Number(0.0)) => Err(self.runtime_error("Division by zero.")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reports message with a trace of the calls in progress, innermost first, and
+    /// unwinds the stack.
+    fn runtime_error(&mut self, message: &str) -> InterpretError {
+        eprintln!("{}", message);
+
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = self.ip;
+        }
+        for frame in self.frames.iter().rev() {
+            // Each ip has already moved past the instruction that failed or made the call.
+            let line = frame.function.chunk.get_line(frame.ip.saturating_sub(1));
+            match &frame.function.name {
+                Some(name) => eprintln!("[line {}] in {}()", line, name),
+                None => eprintln!("[line {}] in script", line),
+            }
+        }
+
+        self.reset_stack();
+        InterpretError::RuntimeError
+    }
+
+    /// Empties the stack, leaving the VM ready to run the next chunk.
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+    }
+}
+
+/// Returns the seconds since the Unix epoch.
+fn clock(_args: &[Value]) -> Value {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Value::Number(elapsed.as_secs_f64())
+}
+
+/// Prints the stack and the instruction about to run to stderr, keeping the program's
+/// own output on stdout clean.
+fn trace(ip: usize, chunk: &Chunk, stack: &[Value]) {
+    let mut trace = String::from("          ");
+    for slot in stack {
+        trace.push_str(&format!("[ {} ]", slot));
+    }
+    let (_, instruction) = chunk.disassemble_instruction(ip);
+    eprint!("{}\n{}", trace, instruction);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// A writer the test keeps a handle to after handing it to the VM.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    /// Interprets source and returns everything it printed.
+    fn run_source(source: &str) -> String {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        assert_eq!(vm.interpret(source), Ok(()));
+        buffer.contents()
+    }
+
+    /// Runs code followed by OP_RETURN and hands back the VM so the stack can be inspected.
+    fn run_code(constants: &[Value], code: &[u8]) -> (VM, Result<(), InterpretError>) {
+        let mut chunk = Chunk::new();
+        for constant in constants {
+            chunk.add_constants(constant.clone());
+        }
+        for byte in code {
+            chunk.write(*byte, 1);
+        }
+        chunk.write(OpCode::Return.into(), 1);
+
+        let mut vm = VM::new();
+        vm.load(chunk);
+        let result = vm.run();
+        (vm, result)
+    }
+
+    #[test]
+    fn dividing_by_a_non_number_is_a_runtime_error() {
+        let mut chunk = Chunk::new();
+        let one = chunk.add_constants(Value::Number(1.0)) as u8;
+        let truth = chunk.add_constants(Value::Bool(true)) as u8;
+        chunk.write(OpCode::Constant.into(), 1);
+        chunk.write(one, 1);
+        chunk.write(OpCode::Constant.into(), 1);
+        chunk.write(truth, 1);
+        chunk.write(OpCode::Divide.into(), 2);
+        chunk.write(OpCode::Return.into(), 2);
+
+        let mut vm = VM::new();
+        vm.load(chunk.clone());
+        assert_eq!(vm.run(), Err(InterpretError::RuntimeError));
+        assert_eq!(chunk.get_line(vm.ip - 1), 2);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn steps_one_instruction_at_a_time() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(2.0), 1);
+        chunk.write_opcode(OpCode::Negate, 1);
+        chunk.write_opcode(OpCode::Return, 1);
+
+        let mut vm = VM::new();
+        vm.load(chunk);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.stack(), [Value::Number(2.0)]);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.stack(), [Value::Number(-2.0)]);
+        assert_eq!(vm.step(), StepResult::Halted);
+        assert_eq!(vm.stack(), [Value::Number(-2.0)]);
+    }
+
+    #[test]
+    fn step_reports_errors() {
+        let mut chunk = Chunk::new();
+        chunk.write_opcode(OpCode::Nil, 1);
+        chunk.write_opcode(OpCode::Negate, 1);
+
+        let mut vm = VM::new();
+        vm.load(chunk);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.step(), StepResult::Error(InterpretError::RuntimeError));
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn hook_runs_before_each_instruction() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::with_writer(Box::new(SharedBuffer::default()));
+        let log = Rc::clone(&seen);
+        vm.set_on_instruction(move |ip, _, stack| log.borrow_mut().push((ip, stack.len())));
+
+        // OP_CONSTANT, OP_CONSTANT, OP_ADD, OP_PRINT, OP_RETURN.
+        assert_eq!(vm.interpret("print \"a\" + \"b\";"), Ok(()));
+        assert_eq!(*seen.borrow(), vec![(0, 0), (2, 1), (4, 2), (5, 1), (6, 0)]);
+    }
+
+    #[test]
+    fn overflowing_the_stack_is_a_runtime_error() {
+        let (vm, result) = run_code(&[], &[OpCode::Nil.into(); STACK_MAX]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.stack_depth(), STACK_MAX);
+
+        let (vm, result) = run_code(&[], &[OpCode::Nil.into(); STACK_MAX + 1]);
+        assert_eq!(result, Err(InterpretError::RuntimeError));
+        assert_eq!(vm.stack_depth(), 0);
+    }
+
+    #[test]
+    fn peek_looks_down_the_stack() {
+        let constant = u8::from(OpCode::Constant);
+        let (mut vm, _) = run_code(
+            &[Value::Number(1.0), Value::Number(2.0)],
+            &[constant, 0, constant, 1],
+        );
+        assert_eq!(vm.peek(0), Ok(Value::Number(2.0)));
+        assert_eq!(vm.peek(1), Ok(Value::Number(1.0)));
+        assert_eq!(vm.stack_depth(), 2);
+        assert_eq!(vm.peek(2), Err(InterpretError::RuntimeError));
+    }
+
+    /// Runs code, with a return appended, after a round trip through serialization.
+    /// Its one constant is a number.
+    fn run_decoded(code: &[u8]) -> (VM, Result<(), InterpretError>) {
+        let mut chunk = Chunk::new();
+        chunk.add_constants(Value::Number(1.0));
+        for byte in code {
+            chunk.write(*byte, 1);
+        }
+        chunk.write_opcode(OpCode::Return, 1);
+        let chunk = Chunk::deserialize(&chunk.serialize()).unwrap();
+
+        let mut vm = VM::new();
+        vm.load(chunk);
+        let result = vm.run();
+        (vm, result)
+    }
+
+    #[test]
+    fn global_names_must_be_strings() {
+        let (vm, result) = run_decoded(&[OpCode::Nil.into(), OpCode::DefineGlobal.into(), 0]);
+        assert_eq!(result, Err(InterpretError::RuntimeError));
+        assert!(vm
+            .globals
+            .values()
+            .all(|value| !matches!(value, Value::Nil)));
+    }
+
+    #[test]
+    fn local_slots_must_be_on_the_stack() {
+        for op in [OpCode::GetLocal, OpCode::SetLocal] {
+            let (vm, result) = run_decoded(&[OpCode::Nil.into(), op.into(), 5]);
+            assert_eq!(result, Err(InterpretError::RuntimeError), "{:?}", op);
+            assert_eq!(vm.stack_depth(), 0);
+        }
+    }
+
+    #[test]
+    fn loops_must_stay_inside_the_code() {
+        let (vm, result) = run_decoded(&[OpCode::Nil.into(), OpCode::Loop.into(), 0, 10]);
+        assert_eq!(result, Err(InterpretError::RuntimeError));
+        assert_eq!(vm.stack_depth(), 0);
+    }
+
+    #[test]
+    fn running_off_the_end_of_the_code_is_a_runtime_error() {
+        let truncated: [&[u8]; 4] = [
+            &[],
+            &[OpCode::Nil.into()],
+            &[OpCode::Nil.into(), OpCode::Constant.into()],
+            &[OpCode::Jump.into(), 0],
+        ];
+        for code in truncated {
+            let mut chunk = Chunk::new();
+            chunk.add_constants(Value::Number(1.0));
+            for byte in code {
+                chunk.write(*byte, 1);
+            }
+
+            let mut vm = VM::new();
+            vm.load(chunk);
+            assert_eq!(vm.run(), Err(InterpretError::RuntimeError), "{:?}", code);
+            assert_eq!(vm.stack_depth(), 0);
+        }
+    }
+
+    #[test]
+    fn underflowing_the_stack_is_a_runtime_error() {
+        for op in [
+            OpCode::Dup,
+            OpCode::Negate,
+            OpCode::Add,
+            OpCode::Print,
+            OpCode::Pop,
+        ] {
+            let (vm, result) = run_code(&[], &[op.into()]);
+            assert_eq!(result, Err(InterpretError::RuntimeError), "{:?}", op);
+            assert_eq!(vm.stack_depth(), 0);
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_compile_errors() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        assert_eq!(
+            vm.interpret("print 1 +;"),
+            Err(InterpretError::CompileError)
+        );
+        assert_eq!(buffer.contents(), "");
+    }
+
+    #[test]
+    fn runs_again_after_a_runtime_error() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+
+        assert_eq!(
+            vm.interpret("print 1 + (2 - nil);"),
+            Err(InterpretError::RuntimeError)
+        );
+        assert_eq!(vm.stack_depth(), 0);
+        assert_eq!(vm.interpret("var x = 1; print x + 1;"), Ok(()));
+        assert_eq!(buffer.contents(), "2\n");
+    }
+
+    #[test]
+    fn unknown_opcode_is_a_runtime_error() {
+        let mut chunk = Chunk::new();
+        chunk.write(99, 1);
+
+        let mut vm = VM::new();
+        vm.load(chunk);
+        assert_eq!(vm.run(), Err(InterpretError::RuntimeError));
+    }
+
+    #[test]
+    fn dup_copies_the_top_of_the_stack() {
+        let (vm, result) = run_code(
+            &[Value::Str("lox".into())],
+            &[
+                OpCode::Nil.into(),
+                OpCode::Constant.into(),
+                0,
+                OpCode::Dup.into(),
+            ],
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            vm.stack,
+            vec![
+                Value::Nil,
+                Value::Str("lox".into()),
+                Value::Str("lox".into())
+            ]
+        );
+
+        let mut code = vec![OpCode::Nil.into(); STACK_MAX];
+        code.push(OpCode::Dup.into());
+        let (_, result) = run_code(&[], &code);
+        assert_eq!(result, Err(InterpretError::RuntimeError));
+    }
+
+    #[test]
+    fn literal_opcodes_push_their_values() {
+        let (vm, result) = run_code(
+            &[],
+            &[
+                OpCode::Nil.into(),
+                OpCode::True.into(),
+                OpCode::False.into(),
+            ],
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            vm.stack,
+            vec![Value::Nil, Value::Bool(true), Value::Bool(false)]
+        );
+    }
+
+    #[test]
+    fn compares_numbers() {
+        let constant = u8::from(OpCode::Constant);
+        let numbers = [Value::Number(1.0), Value::Number(2.0)];
+
+        let (vm, result) = run_code(&numbers, &[constant, 0, constant, 1, OpCode::Less.into()]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.stack, vec![Value::Bool(true)]);
+
+        let (vm, _) = run_code(
+            &numbers,
+            &[constant, 0, constant, 1, OpCode::Greater.into()],
+        );
+        assert_eq!(vm.stack, vec![Value::Bool(false)]);
+    }
+
+    #[test]
+    fn equality_across_types_is_false() {
+        let (vm, result) = run_code(
+            &[],
+            &[
+                OpCode::Nil.into(),
+                OpCode::False.into(),
+                OpCode::Equal.into(),
+            ],
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.stack, vec![Value::Bool(false)]);
+
+        let (vm, _) = run_code(
+            &[],
+            &[OpCode::Nil.into(), OpCode::Nil.into(), OpCode::Equal.into()],
+        );
+        assert_eq!(vm.stack, vec![Value::Bool(true)]);
+    }
+
+    #[test]
+    fn comparing_non_numbers_is_a_runtime_error() {
+        let constant = u8::from(OpCode::Constant);
+        let (_, result) = run_code(
+            &[Value::Number(1.0)],
+            &[OpCode::True.into(), constant, 0, OpCode::Greater.into()],
+        );
+        assert_eq!(result, Err(InterpretError::RuntimeError));
+    }
+
+    #[test]
+    fn not_negates_truthiness() {
+        let not = u8::from(OpCode::Not);
+        let cases = [
+            (vec![OpCode::True.into(), not], false),
+            (vec![OpCode::Nil.into(), not], true),
+            (vec![OpCode::Constant.into(), 0, not], false),
+            (vec![OpCode::Nil.into(), not, not], false),
+        ];
+        for (code, expected) in cases {
+            let (vm, result) = run_code(&[Value::Number(0.0)], &code);
+            assert_eq!(result, Ok(()));
+            assert_eq!(vm.stack, vec![Value::Bool(expected)], "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn concatenates_strings() {
+        let constant = u8::from(OpCode::Constant);
+        let strings = [Value::Str("foo".into()), Value::Str("bar".into())];
+        let (vm, result) = run_code(&strings, &[constant, 0, constant, 1, OpCode::Add.into()]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.stack, vec![Value::Str("foobar".into())]);
+    }
+
+    #[test]
+    fn adding_a_string_and_a_number_is_a_runtime_error() {
+        let constant = u8::from(OpCode::Constant);
+        let operands = [Value::Str("a".into()), Value::Number(1.0)];
+        let (vm, result) = run_code(&operands, &[constant, 0, constant, 1, OpCode::Add.into()]);
+        assert_eq!(result, Err(InterpretError::RuntimeError));
+        assert!(vm.stack.is_empty());
+
+        let (_, result) = run_code(
+            &operands,
+            &[constant, 0, constant, 1, OpCode::Greater.into()],
+        );
+        assert_eq!(result, Err(InterpretError::RuntimeError));
+    }
+
+    #[test]
+    fn operand_type_errors_leave_the_vm_usable() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+
+        for source in ["print -true;", "print 1 + nil;", "print 2 * \"a\";"] {
+            assert_eq!(
+                vm.interpret(source),
+                Err(InterpretError::RuntimeError),
+                "{}",
+                source
+            );
+            assert_eq!(vm.stack_depth(), 0);
+        }
+        assert_eq!(vm.interpret("print -(1 + 2);"), Ok(()));
+        assert_eq!(buffer.contents(), "-3\n");
+    }
+
+    #[test]
+    fn equal_strings_are_equal() {
+        assert_eq!(run_source("print \"lox\" == \"lox\";"), "true\n");
+        // Concatenation builds a separate string to compare against.
+        assert_eq!(run_source("print \"lo\" + \"x\" == \"lox\";"), "true\n");
+    }
+
+    #[test]
+    fn modulo_keeps_the_sign_of_the_dividend() {
+        assert_eq!(
+            run_source("print 7 % 3; print -7 % 3; print 7.5 % 2; print 1 + 7 % 3;"),
+            "1\n-1\n1.5\n2\n"
+        );
+    }
+
+    #[test]
+    fn dividing_by_zero_follows_ieee_by_default() {
+        assert_eq!(
+            run_source("print 1 / 0; print -1 / 0; print 1 % 0 == 1 % 0;"),
+            "inf\n-inf\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn dividing_by_zero_is_an_error_in_strict_mode() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        vm.set_strict_division(true);
+
+        for source in ["print 1 / 0;", "print 1 / -0;", "print 1 % 0;"] {
+            assert_eq!(
+                vm.interpret(source),
+                Err(InterpretError::RuntimeError),
+                "{}",
+                source
+            );
+        }
+        // Type errors still take priority.
+        assert_eq!(vm.interpret("nil / 0;"), Err(InterpretError::RuntimeError));
+        assert_eq!(vm.interpret("print 0 / 2;"), Ok(()));
+        assert_eq!(buffer.contents(), "0\n");
+    }
+
+    #[test]
+    fn loading_a_chunk_starts_with_an_empty_stack() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        let mut leftovers = Chunk::new();
+        leftovers.write_opcode(OpCode::Nil, 1);
+        leftovers.write_opcode(OpCode::Nil, 1);
+        leftovers.write_opcode(OpCode::Return, 1);
+
+        // Script locals start at slot 0, so values left behind would shift them.
+        vm.load(leftovers.clone());
+        assert_eq!(vm.run(), Ok(()));
+        assert_eq!(vm.stack_depth(), 2);
+        assert_eq!(vm.interpret("{ var a = 1; print a; }"), Ok(()));
+
+        vm.load(leftovers);
+        assert_eq!(vm.step(), StepResult::Continue);
+        let locals = Compiler::new("{ var a = \"x\"; print a; }")
+            .compile()
+            .unwrap();
+        vm.load(locals);
+        assert_eq!(vm.run(), Ok(()));
+        assert_eq!(buffer.contents(), "1\nx\n");
+    }
+
+    #[test]
+    fn output_goes_to_the_writer() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+
+        assert_eq!(vm.interpret("print 1 + 2;"), Ok(()));
+        assert_eq!(vm.interpret("print \"a\" + \"b\";"), Ok(()));
+        assert_eq!(buffer.contents(), "3\nab\n");
+    }
+
+    #[test]
+    fn prints_the_value_of_an_expression() {
+        assert_eq!(run_source("print 1 + 2;"), "3\n");
+        assert_eq!(run_source("print nil; print !true;"), "nil\nfalse\n");
+    }
+
+    #[test]
+    fn expression_statements_leave_the_stack_empty() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+
+        assert_eq!(vm.interpret("1 + 2; \"a\";"), Ok(()));
+        assert!(vm.stack.is_empty());
+        assert_eq!(buffer.contents(), "");
+    }
+
+    #[test]
+    fn takes_the_branch_the_condition_picks() {
+        assert_eq!(run_source("if (true) print 1; else print 2;"), "1\n");
+        assert_eq!(run_source("if (nil) print 1; else print 2;"), "2\n");
+        assert_eq!(run_source("if (false) print 1; print 3;"), "3\n");
+
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        assert_eq!(vm.interpret("if (1 < 2) 1; else 2;"), Ok(()));
+        assert_eq!(vm.stack_depth(), 0);
+    }
+
+    #[test]
+    fn runs_a_while_loop() {
+        let output = run_source(
+            "var i = 1; var total = 0;
+             while (i <= 5) total = total + (i = i + 1) - 1;
+             print total;",
+        );
+        assert_eq!(output, "15\n");
+        assert_eq!(run_source("while (false) print 1; print 2;"), "2\n");
+    }
+
+    #[test]
+    fn runs_a_for_loop() {
+        assert_eq!(
+            run_source("for (var i = 0; i < 3; i = i + 1) print i;"),
+            "0\n1\n2\n"
+        );
+        assert_eq!(
+            run_source("var i = 0; for (; i < 2;) print i = i + 1;"),
+            "1\n2\n"
+        );
+    }
+
+    #[test]
+    fn scopes_locals_to_their_block() {
+        let output = run_source(
+            "var a = \"global\";
+             {
+               var a = \"outer\";
+               { var a = \"inner\"; print a; }
+               print a;
+               a = \"changed\";
+               print a;
+             }
+             print a;",
+        );
+        assert_eq!(output, "inner\nouter\nchanged\nglobal\n");
+    }
+
+    #[test]
+    fn leaving_a_block_pops_all_its_locals() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        let result =
+            vm.interpret("var x = 0; { var a = 1; var b = 2; var c = 3; x = a + b + c; } print x;");
+        assert_eq!(result, Ok(()));
+        assert_eq!(vm.stack_depth(), 0);
+        assert_eq!(buffer.contents(), "6\n");
+    }
+
+    #[test]
+    fn for_loop_variable_goes_out_of_scope() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        let result = vm.interpret("for (var i = 0; i < 1; i = i + 1) {} print i;");
+        assert_eq!(result, Err(InterpretError::RuntimeError));
+        assert_eq!(buffer.contents(), "");
+    }
+
+    #[test]
+    fn logical_operators_return_an_operand() {
+        assert_eq!(
+            run_source("print nil or \"default\"; print 1 or 2; print 1 and 2; print false and 2;"),
+            "default\n1\n2\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        let output = run_source(
+            "var x = \"untouched\";
+             false and (x = \"and\");
+             true or (x = \"or\");
+             print x;
+             true and (x = \"evaluated\");
+             print x;",
+        );
+        assert_eq!(output, "untouched\nevaluated\n");
+    }
+
+    #[test]
+    fn calls_recursive_functions() {
+        // This fib adds the leaves of its call tree to a global and implicitly returns nil.
+        let source = "var result = 0;
+                      fun fib(n) {
+                        if (n < 2) result = result + n;
+                        else { fib(n - 1); fib(n - 2); }
+                      }
+                      fib(10);
+                      print result;
+                      print fib;
+                      print fib(1);";
+        assert_eq!(run_source(source), "55\n<fn fib>\nnil\n");
+    }
+
+    #[test]
+    fn returns_values_from_functions() {
+        let source = "fun fib(n) {
+                        if (n < 2) return n;
+                        return fib(n - 1) + fib(n - 2);
+                      }
+                      print fib(20);
+                      fun early(x) { if (x) return; print \"late\"; }
+                      print early(true);
+                      print early(false);";
+        assert_eq!(run_source(source), "6765\nnil\nlate\nnil\n");
+    }
+
+    #[test]
+    fn returning_collapses_the_frame() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        let source =
+            "{ var a = 1; fun f(x, y) { var z = 3; return x + y + z; } print a + f(a, 2); }";
+        assert_eq!(vm.interpret(source), Ok(()));
+        assert_eq!(buffer.contents(), "7\n");
+        assert_eq!(vm.stack_depth(), 0);
+    }
+
+    #[test]
+    fn functions_have_their_own_locals() {
+        let source = "{
+                        var a = \"outer\";
+                        fun show(a, b) { var c = a + b; print c; }
+                        show(\"in\", \"ner\");
+                        print a;
+                      }";
+        assert_eq!(run_source(source), "inner\nouter\n");
+    }
+
+    #[test]
+    fn calling_a_function_with_the_wrong_arity_is_a_runtime_error() {
+        let mut vm = VM::with_writer(Box::new(SharedBuffer::default()));
+        assert_eq!(
+            vm.interpret("fun f(a, b) {} f(1);"),
+            Err(InterpretError::RuntimeError)
+        );
+        assert_eq!(vm.stack_depth(), 0);
+        assert!(vm.frames.is_empty());
+        assert_eq!(vm.interpret("f(1, 2);"), Ok(()));
+    }
+
+    /// Builds a script that nests calls calls to a function with five parameters and two
+    /// more locals.
+    fn recursion_with_locals(calls: usize) -> String {
+        format!(
+            "fun f(n, a, b, c, d) {{
+               var x = a + b;
+               var y = c + d;
+               if (n > 1) f(n - 1, x, y, a, b);
+             }}
+             f({}, 1, 2, 3, 4);",
+            calls
+        )
+    }
+
+    #[test]
+    fn recursion_may_use_every_frame() {
+        let mut vm = VM::with_writer(Box::new(SharedBuffer::default()));
+        // The script's own frame takes up the last one.
+        assert_eq!(vm.interpret(&recursion_with_locals(FRAMES_MAX - 1)), Ok(()));
+        assert_eq!(
+            vm.interpret(&recursion_with_locals(FRAMES_MAX)),
+            Err(InterpretError::RuntimeError)
+        );
+    }
+
+    #[test]
+    fn unbounded_recursion_is_a_stack_overflow() {
+        let mut vm = VM::with_writer(Box::new(SharedBuffer::default()));
+        assert_eq!(
+            vm.interpret("fun f() { f(); } f();"),
+            Err(InterpretError::RuntimeError)
+        );
+        assert!(vm.frames.is_empty());
+    }
+
+    fn sum(args: &[Value]) -> Value {
+        match args {
+            [Value::Number(a), Value::Number(b)] => Value::Number(a + b),
+            _ => Value::Nil,
+        }
+    }
+
+    #[test]
+    fn calls_natives() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        vm.define_native("sum", 2, sum);
+
+        assert_eq!(vm.interpret("print sum(1, sum(2, 3));"), Ok(()));
+        assert_eq!(vm.interpret("print sum;"), Ok(()));
+        assert_eq!(vm.interpret("print clock() > 0;"), Ok(()));
+        assert_eq!(buffer.contents(), "6\n<native fn>\ntrue\n");
+        assert_eq!(vm.stack_depth(), 0);
+    }
+
+    #[test]
+    fn calling_with_the_wrong_arity_is_a_runtime_error() {
+        let mut vm = VM::with_writer(Box::new(SharedBuffer::default()));
+        vm.define_native("sum", 2, sum);
+
+        assert_eq!(vm.interpret("sum(1);"), Err(InterpretError::RuntimeError));
+        assert_eq!(vm.interpret("clock(1);"), Err(InterpretError::RuntimeError));
+        assert_eq!(vm.interpret("sum(1, 2);"), Ok(()));
+    }
+
+    #[test]
+    fn calling_a_non_function_is_a_runtime_error() {
+        let mut vm = VM::with_writer(Box::new(SharedBuffer::default()));
+        assert_eq!(vm.interpret("nil();"), Err(InterpretError::RuntimeError));
+        assert_eq!(vm.interpret("\"f\"(1);"), Err(InterpretError::RuntimeError));
+    }
+
+    #[test]
+    fn defines_and_reads_globals() {
+        assert_eq!(run_source("var x = 5; print x;"), "5\n");
+        assert_eq!(run_source("var x; print x;"), "nil\n");
+    }
+
+    #[test]
+    fn reassigns_globals() {
+        let output = run_source("var x = 1; x = x + 1; print x; print x = 10; print x;");
+        assert_eq!(output, "2\n10\n10\n");
+    }
+
+    #[test]
+    fn globals_survive_between_interpret_calls() {
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        assert_eq!(vm.interpret("var greeting = \"hi\";"), Ok(()));
+        assert_eq!(vm.interpret("print greeting;"), Ok(()));
+        assert_eq!(buffer.contents(), "hi\n");
+    }
+
+    #[test]
+    fn breaks_out_of_loops() {
+        let source = "for (var i = 0; i < 10; i = i + 1) {
+                        var j = i;
+                        if (j == 3) break;
+                        print j;
+                      }
+                      var n = 0;
+                      while (true) { n = n + 1; if (n > 2) { var x; break; } }
+                      print n;";
+        assert_eq!(run_source(source), "0\n1\n2\n3\n");
+    }
+
+    #[test]
+    fn continues_with_the_next_iteration() {
+        let source = "for (var i = 0; i < 6; i = i + 1) {
+                        var odd = i % 2;
+                        if (odd == 0) continue;
+                        print i;
+                      }
+                      var n = 0;
+                      while (n < 4) { n = n + 1; if (n == 2) continue; print n; }";
+        assert_eq!(run_source(source), "1\n3\n5\n1\n3\n4\n");
+    }
+
+    #[test]
+    fn breaks_from_the_innermost_loop() {
+        let source = "for (var i = 0; i < 2; i = i + 1) {
+                        for (var j = 0; j < 5; j = j + 1) { if (j == 1) break; print i + j; }
+                      }";
+        let buffer = SharedBuffer::default();
+        let mut vm = VM::with_writer(Box::new(buffer.clone()));
+        assert_eq!(vm.interpret(source), Ok(()));
+        assert_eq!(buffer.contents(), "0\n1\n");
+        assert_eq!(vm.stack_depth(), 0);
+    }
+
+    #[test]
+    fn evaluates_only_the_chosen_branch() {
+        let source = "print 1 < 2 ? \"yes\" : \"no\";
+                      print nil ? 1 : false ? 2 : 3;
+                      var x = true ? \"a\" : undefined;
+                      print x;";
+        assert_eq!(run_source(source), "yes\n3\na\n");
+    }
+
+    #[test]
+    fn reset_globals_keeps_the_natives() {
+        let mut vm = VM::with_writer(Box::new(SharedBuffer::default()));
+        assert_eq!(vm.interpret("var x = 1;"), Ok(()));
+        vm.reset_globals();
+        assert_eq!(vm.interpret("x;"), Err(InterpretError::RuntimeError));
+        assert_eq!(vm.interpret("clock();"), Ok(()));
+    }
+
+    #[test]
+    fn undefined_variable_is_a_runtime_error() {
+        let mut vm = VM::with_writer(Box::new(SharedBuffer::default()));
+        assert_eq!(vm.interpret("print x;"), Err(InterpretError::RuntimeError));
+        assert_eq!(vm.interpret("x = 1;"), Err(InterpretError::RuntimeError));
+        assert!(!vm.globals.contains_key("x"));
+    }
+}
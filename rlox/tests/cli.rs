@@ -0,0 +1,311 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::{env, fs, process};
+
+/// Writes source to a script file unique to this test run and returns its path.
+fn script(name: &str, source: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("rlox-{}-{}.lox", name, process::id()));
+    fs::write(&path, source).unwrap();
+    path
+}
+
+fn rlox(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Runs the REPL with input on stdin.
+fn repl(input: impl AsRef<[u8]>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_ref())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Decodes what rlox wrote to stderr, minus the execution trace and chunk dumps
+/// that the debug_trace_exec and debug_print_code features write there too.
+fn stderr(bytes: &[u8]) -> String {
+    let stderr = String::from_utf8(bytes.to_vec()).unwrap();
+    if !cfg!(any(
+        feature = "debug_trace_exec",
+        feature = "debug_print_code"
+    )) {
+        return stderr;
+    }
+    // A dump is a "== name ==" header, then one instruction per line, each starting
+    // with its offset. A traced instruction is a line of stack slots, indented past
+    // the offset column, then the instruction.
+    let is_debug = |line: &str| {
+        (line.starts_with("== ") && line.ends_with(" =="))
+            || line.starts_with("          ")
+            || line.len() > 4 && line[..4].bytes().all(|b| b.is_ascii_digit())
+    };
+    stderr
+        .lines()
+        .filter(|line| !is_debug(line))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+#[test]
+fn dump_tokens_prints_each_token() {
+    let path = script("dump-tokens", "var x = 1;\nprint x;");
+    let output = rlox(&["--dump-tokens", path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1:1 VAR 'var'\n\
+         1:5 IDENTIFIER 'x'\n\
+         1:7 EQUAL '='\n\
+         1:9 NUMBER '1'\n\
+         1:10 SEMICOLON ';'\n\
+         2:1 PRINT 'print'\n\
+         2:7 IDENTIFIER 'x'\n\
+         2:8 SEMICOLON ';'\n\
+         2:9 EOF ''\n"
+    );
+}
+
+#[test]
+fn dump_tokens_does_not_run_the_script() {
+    let path = script("dump-tokens-no-run", "print undefined;");
+    let output = rlox(&["--dump-tokens", path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert!(stderr(&output.stderr).is_empty());
+}
+
+#[test]
+fn dump_bytecode_prints_the_disassembly() {
+    let path = script("dump-bytecode", "print \"a\" + \"b\";\nprint -3;");
+    let output = rlox(&["--dump-bytecode", path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "== script ==\n\
+         0000    1 OP_CONSTANT         0 'a'\n\
+         0002    | OP_CONSTANT         1 'b'\n\
+         0004    | OP_ADD\n\
+         0005    | OP_PRINT\n\
+         0006    2 OP_CONSTANT         2 '3'\n\
+         0008    | OP_NEGATE\n\
+         0009    | OP_PRINT\n\
+         0010    | OP_RETURN\n"
+    );
+}
+
+#[test]
+fn dump_bytecode_exits_65_on_a_compile_error() {
+    let path = script("dump-bytecode-error", "print 1 +;");
+    let output = rlox(&["--dump-bytecode", path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(65));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn runs_a_script() {
+    let path = script("run", "print 1 + 2;");
+    let output = rlox(&[path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+}
+
+#[test]
+fn exit_code_tells_compile_and_runtime_errors_apart() {
+    let path = script("compile-error", "print 1 +;\nprint 2;");
+    let output = rlox(&[path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(65));
+    // Nothing runs when the script doesn't compile.
+    assert!(output.stdout.is_empty());
+
+    let path = script("runtime-error", "print 1;\nprint -nil;");
+    let output = rlox(&[path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    assert_eq!(
+        stderr(&output.stderr),
+        "Operand must be a number.\n[line 2] in script\n"
+    );
+}
+
+#[test]
+fn repl_continues_incomplete_input() {
+    let output = repl("print (1 +\n2);\n{\nvar a = \"a\";\nprint a;\n}\n");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "> ... 3\n> ... ... ... a\n> \n"
+    );
+    assert!(stderr(&output.stderr).is_empty());
+}
+
+#[test]
+fn repl_skips_lines_that_are_not_utf8() {
+    let output = repl(b"print 1;\n\xff\nprint 2;\n");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "> 1\n> > 2\n> \n"
+    );
+    assert_eq!(
+        stderr(&output.stderr),
+        "Could not read input: stream did not contain valid UTF-8\n"
+    );
+}
+
+#[test]
+fn repl_gives_up_on_incomplete_input_at_a_blank_line() {
+    let output = repl("print 1\n\nprint 2;\n");
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "> ... > 2\n> \n");
+    assert_eq!(
+        stderr(&output.stderr),
+        "[line 3] Error at end: Expect ';' after value.\n"
+    );
+}
+
+#[test]
+fn dump_flags_need_a_script() {
+    for flag in ["--dump-tokens", "--dump-bytecode"] {
+        let output = rlox(&[flag]);
+        assert_eq!(output.status.code(), Some(64), "{}", flag);
+        assert_eq!(
+            stderr(&output.stderr),
+            "Usage: rlox [--dump-tokens | --dump-bytecode] script | rlox [script]\n"
+        );
+    }
+}
+
+#[test]
+fn missing_file_exits_74() {
+    let path = env::temp_dir().join(format!("rlox-missing-{}.lox", process::id()));
+    let path = path.to_str().unwrap();
+
+    for args in [vec![path], vec!["--dump-tokens", path]] {
+        let output = rlox(&args);
+        assert_eq!(output.status.code(), Some(74));
+        let stderr = stderr(&output.stderr);
+        assert!(
+            stderr.starts_with(&format!("Could not open file \"{}\": ", path)),
+            "{}",
+            stderr
+        );
+        assert_eq!(stderr.lines().count(), 1);
+    }
+}
+
+#[test]
+fn runtime_errors_trace_the_calls_in_progress() {
+    let path = script(
+        "call-trace",
+        "fun inner() {\n  print -nil;\n}\nfun outer() {\n  inner();\n}\nouter();",
+    );
+    let output = rlox(&[path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        stderr(&output.stderr),
+        "Operand must be a number.\n\
+         [line 2] in inner()\n\
+         [line 5] in outer()\n\
+         [line 7] in script\n"
+    );
+}
+
+#[test]
+fn dump_bytecode_includes_functions() {
+    let path = script("dump-bytecode-fun", "fun f() {}");
+    let output = rlox(&["--dump-bytecode", path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "== script ==\n\
+         0000    1 OP_CONSTANT         1 '<fn f>'\n\
+         0002    | OP_DEFINE_GLOBAL    0 'f'\n\
+         0004    | OP_RETURN\n\
+         == <fn f> ==\n\
+         0000    1 OP_NIL\n\
+         0001    | OP_RETURN\n"
+    );
+}
+
+#[test]
+fn repl_quits_on_command() {
+    let output = repl(":quit\nprint 1;\n");
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "> ");
+}
+
+#[test]
+fn repl_runs_commands() {
+    let output = repl(":help\nvar a = -1;\n:dump\n:reset\nprint a;\n:nope\n");
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "> :help    List these commands\n\
+         :quit    Exit the REPL\n\
+         :dump    Disassemble the last entry that compiled\n\
+         :reset   Forget all global variables\n\
+         > > == script ==\n\
+         0000    1 OP_CONSTANT         1 '1'\n\
+         0002    | OP_NEGATE\n\
+         0003    | OP_DEFINE_GLOBAL    0 'a'\n\
+         0005    2 OP_RETURN\n\
+         > > > > \n"
+    );
+    assert_eq!(
+        stderr(&output.stderr),
+        "Undefined variable 'a'.\n\
+         [line 1] in script\n\
+         Unknown command ':nope'. Type :help for a list.\n"
+    );
+}
+
+#[test]
+fn operand_errors_show_the_operands() {
+    let path = script("operand-errors", "print nil + true;");
+    let output = rlox(&[path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        stderr(&output.stderr),
+        "Operands must be two numbers or two strings, got nil and true.\n[line 1] in script\n"
+    );
+
+    let path = script("operand-errors-numeric", "print 1 < nil;");
+    let output = rlox(&[path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        stderr(&output.stderr),
+        "Operands must be numbers, got 1 and nil.\n[line 1] in script\n"
+    );
+}